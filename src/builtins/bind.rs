@@ -0,0 +1,153 @@
+use std::io::BufReader;
+use std::fs;
+use std::rc;
+
+use opts;
+
+use editor::Binding;
+use editor::EditCmd;
+use editor::KEYMAP_NAMES;
+use exec::Arg;
+use shell::Shell;
+
+const USAGE: &str = "bind: usage: bind [-m keymap] [keys action | -r keys] | bind -l";
+
+// reads keys written as in readline: \e for escape, ^X for control-X, and
+// \n, \r, \t, \xHH, \\ and \^ as usual
+fn parse_keys(s: &str) -> Option<String> {
+    let mut ret = String::new();
+    let mut cs = s.chars();
+    while let Some(c) = cs.next() {
+        match c {
+            '\\' => {
+                match cs.next() {
+                    Some('e') => ret.push('\x1b'),
+                    Some('n') => ret.push('\n'),
+                    Some('r') => ret.push('\r'),
+                    Some('t') => ret.push('\t'),
+                    Some('x') => {
+                        let hex = cs.by_ref().take(2).collect::<String>();
+                        match u8::from_str_radix(&hex, 16) {
+                            Ok(b) if b < 0x80 => ret.push(b as char),
+                            _ => return None,
+                        }
+                    }
+                    Some(c) => ret.push(c),
+                    None => return None,
+                }
+            }
+            '^' => {
+                match cs.next() {
+                    Some('?') => ret.push('\x7f'),
+                    Some(c) if c.is_ascii() && c.to_ascii_uppercase() >= '@' &&
+                               c.to_ascii_uppercase() <= '_' => {
+                        ret.push(((c.to_ascii_uppercase() as u8) & 0x1f) as char)
+                    }
+                    _ => return None,
+                }
+            }
+            c => ret.push(c),
+        }
+    }
+    if ret.is_empty() { None } else { Some(ret) }
+}
+
+// writes keys the way parse_keys reads them
+fn show_keys(keys: &str) -> String {
+    let mut ret = String::new();
+    for c in keys.chars() {
+        match c {
+            '\x1b' => ret.push_str("\\e"),
+            '\x7f' => ret.push_str("^?"),
+            '\\' => ret.push_str("\\\\"),
+            '^' => ret.push_str("\\^"),
+            '\'' => ret.push_str("\\x27"),
+            c if (c as u32) < 0x20 => {
+                ret.push('^');
+                ret.push(((c as u8) + 0x40) as char);
+            }
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+/// `bind` lists the key bindings of a keymap; `bind keys action` binds keys
+/// to an editing command (see `bind -l`) or to a fn, which sees the line
+/// and cursor in _line and _cursor, and may change them with `set -g`;
+/// `bind -r keys` unbinds them.  keys may be a sequence, like '^X^E': once
+/// its start is typed, the prompt waits a moment for the rest.
+pub fn bind_main() -> rc::Rc<Fn(Vec<Arg>, &mut Shell, Option<BufReader<fs::File>>) -> i32> {
+    rc::Rc::new(|args: Vec<Arg>, sh: &mut Shell, _in: Option<BufReader<fs::File>>| -> i32 {
+        let mut av = args.into_iter().flat_map(|x| x.into_vec()).collect::<Vec<_>>();
+
+        if av.len() == 1 && av[0] == "-l" {
+            for n in EditCmd::names() {
+                println!("{}", n);
+            }
+            return 0;
+        }
+
+        // by default, the keymap used when typing a line
+        let mut map = match opts::get("__tin_editmode").as_ref().map(|x| x as &str) {
+            Some("vi") => "vi-insert".to_string(),
+            _ => "emacs".to_string(),
+        };
+        if av.len() >= 2 && av[0] == "-m" {
+            av.remove(0);
+            map = av.remove(0);
+        }
+        let km = match sh.km.get_mut(&map) {
+            Some(km) => km,
+            None => {
+                warn!("bind: '{}' is not a keymap ({})", map, KEYMAP_NAMES.join(", "));
+                return 2;
+            }
+        };
+
+        match av.len() {
+            0 => {
+                let mut binds = km.iter().collect::<Vec<_>>();
+                binds.sort_by(|a, b| a.0.cmp(b.0));
+                for (k, b) in binds {
+                    let action = match *b {
+                        Binding::Cmd(c) => c.name(),
+                        Binding::Fn(ref f) => f,
+                    };
+                    println!("bind -m {} '{}' {}", map, show_keys(k), action);
+                }
+                0
+            }
+            2 => {
+                let remove = av[0] == "-r";
+                let ks = if remove { &av[1] } else { &av[0] };
+                let keys = match parse_keys(ks) {
+                    Some(k) => k,
+                    None => {
+                        warn!("bind: bad key sequence '{}'", ks);
+                        return 1;
+                    }
+                };
+                if remove {
+                    if km.remove(&keys).is_none() {
+                        warn!("bind: '{}' is not bound", av[1]);
+                        return 1;
+                    }
+                    return 0;
+                }
+
+                // anything which isn't an editing command is taken to be a fn
+                let b = match EditCmd::from_name(&av[1]) {
+                    Some(c) => Binding::Cmd(c),
+                    None => Binding::Fn(av[1].clone()),
+                };
+                km.insert(keys, b);
+                0
+            }
+            _ => {
+                warn!("{}", USAGE);
+                2
+            }
+        }
+    })
+}
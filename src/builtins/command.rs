@@ -0,0 +1,163 @@
+use std::io::BufReader;
+use std::fs;
+use std::rc;
+
+use sym;
+
+use builtins::fnargs;
+use exec::Arg;
+use exec::ProcStruct;
+use exec::Process;
+use exec::binprocess::BinProcess;
+use exec::builtinprocess::adapt_args;
+use exec::job::Job;
+use shell::Shell;
+
+// runs a binary from within a builtin, as if it had been run by itself
+fn run_binary(sh: &mut Shell, name: &str, path: ::std::path::PathBuf, args: Vec<Arg>) -> i32 {
+    let mut bp = BinProcess::new(&name.to_string(), path);
+    for a in args {
+        bp.push_arg(a);
+    }
+
+    let mut job = Job::new(name.to_string());
+    job.procs.push(Box::new(ProcStruct::BinProc(bp)));
+    sh.exec(job);
+    sh.status_code
+}
+
+/// `command name args...` runs name as a builtin or binary, skipping any fn
+/// which has the same name.
+pub fn command_main() -> rc::Rc<Fn(Vec<Arg>, &mut Shell, Option<BufReader<fs::File>>) -> i32> {
+    rc::Rc::new(|mut args: Vec<Arg>, sh: &mut Shell, _in: Option<BufReader<fs::File>>| -> i32 {
+        if args.is_empty() {
+            return 0;
+        }
+
+        let name = args.remove(0).into_string();
+        if let Some(b) = sh.st.resolve_builtin(&name) {
+            (*b.run)(adapt_args(&b, args), sh, None)
+        } else if let Some(path) = sh.st.resolve_binary(&name) {
+            run_binary(sh, &name, path, args)
+        } else {
+            warn!("command: '{}' not found", name);
+            127
+        }
+    })
+}
+
+/// `builtin name args...` runs the builtin name, whatever else it may be shadowed by.
+pub fn builtin_main() -> rc::Rc<Fn(Vec<Arg>, &mut Shell, Option<BufReader<fs::File>>) -> i32> {
+    rc::Rc::new(|mut args: Vec<Arg>, sh: &mut Shell, _in: Option<BufReader<fs::File>>| -> i32 {
+        if args.is_empty() {
+            return 0;
+        }
+
+        let name = args.remove(0).into_string();
+        match sh.st.resolve_builtin(&name) {
+            Some(b) => (*b.run)(adapt_args(&b, args), sh, None),
+            None => {
+                warn!("builtin: '{}' is not a builtin", name);
+                2
+            }
+        }
+    })
+}
+
+/// `type names...` tells what each name could be run as, in the order they're tried.
+pub fn type_main() -> rc::Rc<Fn(Vec<Arg>, &mut Shell, Option<BufReader<fs::File>>) -> i32> {
+    rc::Rc::new(|args: Vec<Arg>, sh: &mut Shell, _in: Option<BufReader<fs::File>>| -> i32 {
+        let mut r = 0;
+        for name in args.into_iter().flat_map(|x| x.into_vec()) {
+            let mut found = false;
+            for s in sh.st.resolve_all(&name) {
+                match s {
+                    sym::Sym::Fn(f) => {
+                        println!("{} is a fn:", name);
+                        println!("set {} = {}", name, fnargs::fn_decl(&f));
+                    }
+                    sym::Sym::Builtin(b) => println!("{} is a builtin: {}", name, b.desc),
+                    sym::Sym::Binary(p) => println!("{} is {}", name, p.display()),
+                    // vars aren't run
+                    sym::Sym::Var(_) |
+                    sym::Sym::Environment(_) => continue,
+                }
+                found = true;
+            }
+
+            if !found {
+                warn!("type: '{}' not found", name);
+                r = 1;
+            }
+        }
+        r
+    })
+}
+
+/// `which names...` tells just what each name would be run as: the path of a
+/// binary, or whether it's a fn or a builtin.
+pub fn which_main() -> rc::Rc<Fn(Vec<Arg>, &mut Shell, Option<BufReader<fs::File>>) -> i32> {
+    rc::Rc::new(|args: Vec<Arg>, sh: &mut Shell, _in: Option<BufReader<fs::File>>| -> i32 {
+        let mut r = 0;
+        for name in args.into_iter().flat_map(|x| x.into_vec()) {
+            let first = sh.st.resolve_all(&name).into_iter().find(|s| {
+                !matches!(*s, sym::Sym::Var(_) | sym::Sym::Environment(_))
+            });
+            match first {
+                Some(sym::Sym::Fn(_)) => println!("{}: fn", name),
+                Some(sym::Sym::Builtin(_)) => println!("{}: builtin", name),
+                Some(sym::Sym::Binary(p)) => println!("{}", p.display()),
+                _ => {
+                    warn!("which: '{}' not found", name);
+                    r = 1;
+                }
+            }
+        }
+        r
+    })
+}
+
+/// `hash` lists the binaries looked up so far; `hash names...` looks them up,
+/// `hash -p path name` remembers path for name, `hash -d names...` forgets
+/// them and `hash -r` hashes PATH over again.
+pub fn hash_main() -> rc::Rc<Fn(Vec<Arg>, &mut Shell, Option<BufReader<fs::File>>) -> i32> {
+    rc::Rc::new(|args: Vec<Arg>, sh: &mut Shell, _in: Option<BufReader<fs::File>>| -> i32 {
+        let mut av = args.into_iter().flat_map(|x| x.into_vec()).collect::<Vec<_>>();
+        if av.is_empty() {
+            for (n, p) in sh.st.hashed() {
+                println!("hash -p {} {}", p.display(), n);
+            }
+            return 0;
+        }
+
+        let mut r = 0;
+        match &av[0] as &str {
+            "-r" if av.len() == 1 => sh.st.rehash(),
+            "-p" if av.len() == 3 => {
+                let n = av.remove(2);
+                sh.st.hash_add(&n, ::std::path::PathBuf::from(av.remove(1)));
+            }
+            "-d" => {
+                for n in av.iter().skip(1) {
+                    if !sh.st.hash_forget(n) {
+                        warn!("hash: '{}' is not hashed", n);
+                        r = 1;
+                    }
+                }
+            }
+            a if a.starts_with('-') => {
+                warn!("hash: usage: hash [names...] | hash -p path name | hash -d names... | hash -r");
+                return 2;
+            }
+            _ => {
+                for n in av.iter() {
+                    if sh.st.resolve_binary(n).is_none() {
+                        warn!("hash: '{}' not found", n);
+                        r = 1;
+                    }
+                }
+            }
+        }
+        r
+    })
+}
@@ -0,0 +1,96 @@
+use std::io;
+use std::io::BufReader;
+use std::io::Write;
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::process::exit;
+use std::ptr;
+use std::rc;
+
+use posix;
+use opts;
+use sym;
+
+use builtins;
+use exec;
+use exec::Arg;
+use exec::Redir;
+use shell::Shell;
+
+// when exec can't go ahead, a non-interactive shell has nowhere to go back to
+fn exec_fail(code: i32) -> i32 {
+    if opts::is_set("__tin_inter") {
+        code
+    } else {
+        exit(code)
+    }
+}
+
+/// `exec cmd args...` replaces the shell with cmd; `exec` with nothing but
+/// redirections applies them to the shell for good.
+pub fn exec_main() -> rc::Rc<Fn(Vec<Arg>, &mut Shell, Option<BufReader<fs::File>>) -> i32> {
+    rc::Rc::new(|args: Vec<Arg>, sh: &mut Shell, _in: Option<BufReader<fs::File>>| -> i32 {
+        let mut rds: Vec<Redir> = Vec::new();
+        let mut av: Vec<String> = Vec::new();
+        for a in args {
+            match a {
+                Arg::Rd(rd) => rds.push(rd),
+                a => av.extend(a.into_vec()),
+            }
+        }
+
+        let _ = io::stdout().flush();
+        if let Err(e) = exec::redirect_shell(rds, false) {
+            warn!("exec: Could not redirect: {}", e);
+            return exec_fail(1);
+        }
+        if av.is_empty() {
+            return 0;
+        }
+
+        match sh.st.resolve_exec(&av[0]) {
+            Some(sym::SymE::Binary(path)) => {
+                let cargs = match av.iter().map(|a| CString::new(a.as_bytes())).collect::<Result<Vec<_>, _>>() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        warn!("exec: args cannot contain NUL");
+                        return exec_fail(2);
+                    }
+                };
+                let mut argv = cargs.iter().map(|c| c.as_ptr()).collect::<Vec<_>>();
+                argv.push(ptr::null());
+                let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+
+                // the new program shouldn't inherit the shell's indifference to signals
+                if opts::is_set("__tin_inter") {
+                    let _ = posix::set_signal_ignore(false);
+                }
+
+                let e = posix::execv(cpath.as_ptr(), argv.as_ptr());
+                if opts::is_set("__tin_inter") {
+                    let _ = posix::set_signal_ignore(true);
+                }
+                warn!("exec: {}: {}", path.display(), e);
+                exec_fail(126)
+            }
+            // there's nothing to replace the shell with, so run it and leave
+            Some(sym::SymE::Builtin(b)) => {
+                let r = (*b.run)(av.into_iter().skip(1).map(Arg::Str).collect(), sh, None);
+                let _ = io::stdout().flush();
+                exit(r)
+            }
+            Some(sym::SymE::Fn(f)) => {
+                let r = (*builtins::fn_builtin(*f).run)(av.into_iter().skip(1).map(Arg::Str).collect(),
+                                                      sh,
+                                                      None);
+                let _ = io::stdout().flush();
+                exit(r)
+            }
+            None => {
+                warn!("exec: '{}' not found", av[0]);
+                exec_fail(127)
+            }
+        }
+    })
+}
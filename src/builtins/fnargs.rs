@@ -0,0 +1,509 @@
+use std::mem;
+use std::path;
+
+use sym;
+
+// checks an argument against a parameter's pattern, if it has one
+fn check_param(p: &sym::Param, val: &str) -> Result<(), String> {
+    let ok = match p.pat {
+        None => true,
+        Some(sym::ParamPat::Int) => val.parse::<i64>().is_ok(),
+        Some(sym::ParamPat::File) => path::Path::new(val).is_file(),
+        Some(sym::ParamPat::Dir) => path::Path::new(val).is_dir(),
+        Some(sym::ParamPat::Regex(ref re)) => re.is_match(val),
+    };
+
+    if ok {
+        Ok(())
+    } else {
+        Err(format!("arg '{}' ('{}') is not {}",
+                    p.name,
+                    val,
+                    match p.pat {
+                        Some(sym::ParamPat::Int) => "an int".to_string(),
+                        Some(sym::ParamPat::File) => "a file".to_string(),
+                        Some(sym::ParamPat::Dir) => "a directory".to_string(),
+                        Some(sym::ParamPat::Regex(ref re)) => format!("like '{}'", re.as_str()),
+                        None => unreachable!(),
+                    }))
+    }
+}
+
+// Works out which param gets which arg.  Required params are always filled;
+// whatever args are left over fill optional params (and [] groups, all or
+// nothing) from left to right, and then the vararg.
+pub fn bind_args(f: &sym::Fn, mut av: Vec<String>) -> Result<Vec<(String, String)>, String> {
+    let no_post = Vec::new();
+    let fixed = f.args.iter().chain(f.postargs.as_ref().unwrap_or(&no_post)).collect::<Vec<_>>();
+
+    let required = fixed.iter().filter(|p| !p.opt).collect::<Vec<_>>();
+    let va_min = match f.vararg {
+        Some(ref va) if !va.opt => 1,
+        _ => 0,
+    };
+    if av.len() < required.len() {
+        return Err(format!("missing arg '{}'", required[av.len()].name));
+    } else if av.len() < required.len() + va_min {
+        return Err(format!("missing arg '{}'", f.vararg.as_ref().unwrap().name));
+    }
+
+    let mut extra = av.len() - required.len() - va_min;
+    let mut filled = vec![false; fixed.len()];
+    let mut i = 0;
+    while i < fixed.len() {
+        let p = fixed[i];
+        if let Some(g) = p.group {
+            let size = fixed[i..].iter().take_while(|q| q.group == Some(g)).count();
+            if extra >= size {
+                extra -= size;
+                for x in &mut filled[i..i + size] {
+                    *x = true;
+                }
+            }
+            i += size;
+        } else {
+            if !p.opt {
+                filled[i] = true;
+            } else if extra > 0 {
+                extra -= 1;
+                filled[i] = true;
+            }
+            i += 1;
+        }
+    }
+
+    if f.vararg.is_none() && extra > 0 {
+        return Err(format!("too many args (takes at most {})", fixed.len()));
+    }
+
+    let mut res = Vec::new();
+    for (j, p) in f.args.iter().enumerate() {
+        res.push((p.name.clone(), try!(take_arg(p, filled[j], &mut av))));
+    }
+    if let Some(ref va) = f.vararg {
+        let rest = av.drain(..va_min + extra).collect::<Vec<_>>();
+        for a in &rest {
+            try!(check_param(va, a));
+        }
+        res.push((va.name.clone(), rest.join(" ")));
+    }
+    for (j, p) in f.postargs.as_ref().unwrap_or(&no_post).iter().enumerate() {
+        let filled = filled[f.args.len() + j];
+        res.push((p.name.clone(), try!(take_arg(p, filled, &mut av))));
+    }
+
+    Ok(res)
+}
+
+// takes the next arg for p if it was given one, or else its default
+fn take_arg(p: &sym::Param, filled: bool, av: &mut Vec<String>) -> Result<String, String> {
+    if filled {
+        let val = av.remove(0);
+        try!(check_param(p, &val));
+        Ok(val)
+    } else {
+        Ok(p.default.clone().unwrap_or_default())
+    }
+}
+
+
+// the value given to a flag which takes one, either attached or as the next arg
+fn flag_val<I: Iterator<Item = String>>(name: &str,
+                                        attached: Option<&str>,
+                                        av: &mut I)
+                                        -> Result<String, String> {
+    match attached {
+        Some(v) => Ok(v.to_string()),
+        None => av.next().ok_or(format!("flag '{}' requires a value", name)),
+    }
+}
+
+/// Takes the flags declared by f out of av, which may be given anywhere before
+/// a '--'.  Returns the value of each of f's flags by its var name, or None if
+/// the user asked for '--help' instead.
+pub fn parse_flags(f: &sym::Fn, av: &mut Vec<String>) -> Result<Option<Vec<(String, String)>>, String> {
+    if f.flags.is_empty() {
+        // a fn without flags takes its args as they are, '--help' and all
+        return Ok(Some(Vec::new()));
+    }
+
+    let mut vals = f.flags.iter().map(|fl| fl.val.clone().unwrap_or(String::new())).collect::<Vec<_>>();
+    let mut rest = Vec::new();
+    let mut it = mem::take(av).into_iter();
+
+    while let Some(a) = it.next() {
+        if a == "--" {
+            rest.extend(it);
+            break;
+        } else if let Some(long) = a.strip_prefix("--") {
+            let (name, attached) = match long.find('=') {
+                Some(n) => (&long[..n], Some(&long[n + 1..])),
+                None => (long, None),
+            };
+            let i = match f.flags.iter().position(|fl| fl.long.as_ref().map(|l| l as &str) == Some(name)) {
+                Some(i) => i,
+                None if name == "help" => return Ok(None),
+                None => return Err(format!("unknown flag '--{}'", name)),
+            };
+
+            vals[i] = if f.flags[i].val.is_some() {
+                try!(flag_val(&a, attached, &mut it))
+            } else if attached.is_some() {
+                return Err(format!("flag '--{}' does not take a value", name));
+            } else {
+                "y".to_string()
+            };
+        } else if a.starts_with('-') && a.len() > 1 && a.parse::<f64>().is_err() {
+            // bundled short flags; one which takes a value takes the rest of the arg
+            for (j, c) in a.char_indices().skip(1) {
+                let i = match f.flags.iter().position(|fl| fl.short == Some(c)) {
+                    Some(i) => i,
+                    None => return Err(format!("unknown flag '-{}'", c)),
+                };
+
+                if f.flags[i].val.is_some() {
+                    let attached = &a[j + c.len_utf8()..];
+                    let attached = if attached.is_empty() { None } else { Some(attached) };
+                    vals[i] = try!(flag_val(&format!("-{}", c), attached, &mut it));
+                    break;
+                }
+                vals[i] = "y".to_string();
+            }
+        } else {
+            rest.push(a);
+        }
+    }
+
+    *av = rest;
+    Ok(Some(f.flags.iter().map(|fl| fl.var()).zip(vals).collect()))
+}
+
+// how a param looks in a declaration
+fn param_decl(p: &sym::Param) -> String {
+    let mut s = p.name.clone();
+    if p.opt && p.group.is_none() && p.default.is_none() {
+        s.push('?');
+    }
+    match p.pat {
+        Some(sym::ParamPat::Int) => s.push_str(":int"),
+        Some(sym::ParamPat::File) => s.push_str(":file"),
+        Some(sym::ParamPat::Dir) => s.push_str(":dir"),
+        _ => {}
+    }
+    if let Some(sym::ParamPat::Regex(ref re)) = p.pat {
+        s.push('~');
+        s.push_str(re.as_str());
+    }
+    if let Some(ref d) = p.default {
+        s.push('=');
+        s.push_str(d);
+    }
+    s
+}
+
+// how a list of params, including any [] groups, looks in a declaration
+fn params_decl(ps: &[sym::Param], decl: &mut Vec<String>) {
+    let mut i = 0;
+    while i < ps.len() {
+        match ps[i].group {
+            Some(g) => {
+                let grp = ps[i..].iter().take_while(|q| q.group == Some(g)).map(param_decl).collect::<Vec<_>>();
+                i += grp.len();
+                decl.push(format!("[{}]", grp.join(" ")));
+            }
+            None => {
+                decl.push(param_decl(&ps[i]));
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Rebuilds the declaration of f, as it could have been written.
+pub fn fn_decl(f: &sym::Fn) -> String {
+    let mut decl = vec!["fn".to_string()];
+    if f.inline {
+        decl.push("--inline".to_string());
+    }
+
+    for fl in &f.flags {
+        let mut names = Vec::new();
+        if let Some(s) = fl.short {
+            names.push(format!("-{}", s));
+        }
+        if let Some(ref l) = fl.long {
+            names.push(format!("--{}", l));
+        }
+        let mut s = names.join("/");
+        if let Some(ref v) = fl.val {
+            s.push('=');
+            s.push_str(v);
+        }
+        decl.push(s);
+    }
+
+    params_decl(&f.args, &mut decl);
+    if let Some(ref va) = f.vararg {
+        let mut s = param_decl(va);
+        s.push_str("...");
+        decl.push(s);
+    }
+    if let Some(ref post) = f.postargs {
+        params_decl(post, &mut decl);
+    }
+
+    let mut res = format!("{} {{\n", decl.join(" "));
+    for l in f.lines.iter().skip_while(|l| l.is_empty()) {
+        if !l.is_empty() {
+            res.push_str("    ");
+            res.push_str(l);
+        }
+        res.push('\n');
+    }
+    while res.ends_with("\n\n") {
+        res.pop();
+    }
+    res.push('}');
+    res
+}
+
+// how a param looks in a usage line
+fn param_usage(p: &sym::Param) -> String {
+    match p.default {
+        Some(ref d) => format!("{}={}", p.name, d),
+        None => p.name.clone(),
+    }
+}
+
+// how a list of params, including any [] groups, looks in a usage line
+fn params_usage(ps: &[sym::Param], usage: &mut Vec<String>) {
+    let mut i = 0;
+    while i < ps.len() {
+        if let Some(g) = ps[i].group {
+            let grp = ps[i..].iter().take_while(|q| q.group == Some(g)).map(param_usage).collect::<Vec<_>>();
+            i += grp.len();
+            usage.push(format!("[{}]", grp.join(" ")));
+        } else {
+            if ps[i].opt {
+                usage.push(format!("[{}]", param_usage(&ps[i])));
+            } else {
+                usage.push(param_usage(&ps[i]));
+            }
+            i += 1;
+        }
+    }
+}
+
+/// Prints the usage of f, built from its declaration, and its doc comment.
+pub fn print_help(f: &sym::Fn) {
+    let mut usage = vec![f.name.clone()];
+
+    for fl in &f.flags {
+        let names = match (fl.short, fl.long.as_ref()) {
+            (Some(s), Some(l)) => format!("-{}|--{}", s, l),
+            (Some(s), None) => format!("-{}", s),
+            (None, Some(l)) => format!("--{}", l),
+            (None, None) => unreachable!(),
+        };
+        if fl.val.is_some() {
+            usage.push(format!("[{} {}]", names, fl.var().to_uppercase()));
+        } else {
+            usage.push(format!("[{}]", names));
+        }
+    }
+
+    params_usage(&f.args, &mut usage);
+    if let Some(ref va) = f.vararg {
+        if va.opt {
+            usage.push(format!("[{}...]", va.name));
+        } else {
+            usage.push(format!("{}...", va.name));
+        }
+    }
+    if let Some(ref post) = f.postargs {
+        params_usage(post, &mut usage);
+    }
+
+    println!("usage: {}", usage.join(" "));
+    if let Some(ref doc) = f.doc {
+        println!();
+        println!("{}", doc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn p(name: &str) -> sym::Param {
+        sym::Param {
+            name: name.to_string(),
+            opt: false,
+            group: None,
+            default: None,
+            pat: None,
+        }
+    }
+
+    fn grouped(name: &str, g: usize) -> sym::Param {
+        sym::Param { opt: true, group: Some(g), ..p(name) }
+    }
+
+    fn dflt(name: &str, d: &str) -> sym::Param {
+        sym::Param { opt: true, default: Some(d.to_string()), ..p(name) }
+    }
+
+    fn func(args: Vec<sym::Param>, vararg: Option<sym::Param>, postargs: Vec<sym::Param>) -> sym::Fn {
+        sym::Fn {
+            name: "f".to_string(),
+            inline: false,
+            flags: Vec::new(),
+            args,
+            postargs: if vararg.is_some() { Some(postargs) } else { None },
+            vararg,
+            lines: Vec::new(),
+            doc: None,
+            module: None,
+            env: sym::Closure::default(),
+            file: None,
+            line: 0,
+        }
+    }
+
+    // the args bound, as 'name=val' in order
+    fn bind(f: &sym::Fn, av: &[&str]) -> Result<String, String> {
+        let av = av.iter().map(|a| a.to_string()).collect();
+        bind_args(f, av).map(|vals| {
+            vals.iter().map(|(n, v)| format!("{}={}", n, v)).collect::<Vec<_>>().join(" ")
+        })
+    }
+
+    fn flag(short: Option<char>, long: Option<&str>, val: Option<&str>) -> sym::Flag {
+        sym::Flag {
+            short,
+            long: long.map(|l| l.to_string()),
+            val: val.map(|v| v.to_string()),
+        }
+    }
+
+    // -a -b= -o/--output=out --x= -v/--verbose
+    fn flagged() -> sym::Fn {
+        sym::Fn {
+            flags: vec![flag(Some('a'), None, None),
+                        flag(Some('b'), None, Some("")),
+                        flag(Some('o'), Some("output"), Some("out")),
+                        flag(None, Some("x"), Some("")),
+                        flag(Some('v'), Some("verbose"), None)],
+            ..func(vec![], Some(sym::Param { opt: true, ..p("args") }), vec![])
+        }
+    }
+
+    // the flags set, as 'var=val', and the args left
+    fn flags(f: &sym::Fn, av: &[&str]) -> Result<Option<(String, Vec<String>)>, String> {
+        let mut av = av.iter().map(|a| a.to_string()).collect();
+        let vals = try!(parse_flags(f, &mut av));
+        Ok(vals.map(|vals| {
+            let set = vals.iter()
+                          .filter(|(_, v)| !v.is_empty())
+                          .map(|(n, v)| format!("{}={}", n, v))
+                          .collect::<Vec<_>>();
+            (set.join(" "), av)
+        }))
+    }
+
+    fn set(vals: &str, av: &[&str]) -> Result<Option<(String, Vec<String>)>, String> {
+        Ok(Some((vals.to_string(), av.iter().map(|a| a.to_string()).collect())))
+    }
+
+    #[test]
+    fn parses_flags() {
+        let f = flagged();
+        assert_eq!(flags(&f, &[]), set("output=out", &[]));
+        // flags may come anywhere, and bundled, with a value taking the rest
+        assert_eq!(flags(&f, &["x", "-abVAL", "y"]), set("a=y b=VAL output=out", &["x", "y"]));
+        assert_eq!(flags(&f, &["-ob", "-a"]), set("a=y output=b", &[]));
+        assert_eq!(flags(&f, &["-o", "-a"]), set("output=-a", &[]));
+        assert_eq!(flags(&f, &["--x=v", "--verbose"]), set("output=out x=v verbose=y", &[]));
+        assert_eq!(flags(&f, &["--x", "v=w"]), set("output=out x=v=w", &[]));
+        assert_eq!(flags(&f, &["--output="]), set("", &[]));
+        // and stop at '--'
+        assert_eq!(flags(&f, &["-a", "--", "-b", "--"]), set("a=y output=out", &["-b", "--"]));
+        assert_eq!(flags(&f, &["--help"]), Ok(None));
+    }
+
+    #[test]
+    fn leaves_numbers_and_dashes() {
+        let f = flagged();
+        assert_eq!(flags(&f, &["-5", "-1.5", "-"]), set("output=out", &["-5", "-1.5", "-"]));
+        assert_eq!(flags(&f, &["-o", "-5"]), set("output=-5", &[]));
+    }
+
+    #[test]
+    fn refuses_bad_flags() {
+        let f = flagged();
+        assert_eq!(flags(&f, &["-aq"]), Err("unknown flag '-q'".to_string()));
+        assert_eq!(flags(&f, &["--nope"]), Err("unknown flag '--nope'".to_string()));
+        assert_eq!(flags(&f, &["-ao"]), Err("flag '-o' requires a value".to_string()));
+        assert_eq!(flags(&f, &["--output"]), Err("flag '--output' requires a value".to_string()));
+        assert_eq!(flags(&f, &["--verbose=1"]),
+                   Err("flag '--verbose' does not take a value".to_string()));
+    }
+
+    #[test]
+    fn passes_everything_on_without_flags() {
+        let f = func(vec![], Some(sym::Param { opt: true, ..p("args") }), vec![]);
+        assert_eq!(flags(&f, &["--help", "-a", "--"]), set("", &["--help", "-a", "--"]));
+    }
+
+    #[test]
+    fn binds_groups_all_or_nothing() {
+        // f a [b c] d=7
+        let f = func(vec![p("a"), grouped("b", 1), grouped("c", 1), dflt("d", "7")], None, vec![]);
+        assert_eq!(bind(&f, &["1"]), Ok("a=1 b= c= d=7".to_string()));
+        // one left over isn't enough for the group, so it goes on to d
+        assert_eq!(bind(&f, &["1", "2"]), Ok("a=1 b= c= d=2".to_string()));
+        assert_eq!(bind(&f, &["1", "2", "3"]), Ok("a=1 b=2 c=3 d=7".to_string()));
+        assert_eq!(bind(&f, &["1", "2", "3", "4"]), Ok("a=1 b=2 c=3 d=4".to_string()));
+        assert_eq!(bind(&f, &["1", "2", "3", "4", "5"]),
+                   Err("too many args (takes at most 4)".to_string()));
+        assert_eq!(bind(&f, &[]), Err("missing arg 'a'".to_string()));
+
+        // a group's params can have defaults of their own
+        let f = func(vec![sym::Param { default: Some("3".to_string()), ..grouped("b", 1) },
+                          grouped("c", 1)],
+                     None,
+                     vec![]);
+        assert_eq!(bind(&f, &[]), Ok("b=3 c=".to_string()));
+    }
+
+    #[test]
+    fn binds_varargs_between_args() {
+        // f a rest... last
+        let f = func(vec![p("a")], Some(p("rest")), vec![p("last")]);
+        assert_eq!(bind(&f, &["1", "2", "x"]), Ok("a=1 rest=2 last=x".to_string()));
+        assert_eq!(bind(&f, &["1", "2", "3", "x"]), Ok("a=1 rest=2 3 last=x".to_string()));
+        assert_eq!(bind(&f, &["1", "x"]), Err("missing arg 'rest'".to_string()));
+        assert_eq!(bind(&f, &["1"]), Err("missing arg 'last'".to_string()));
+
+        // f a rest?... [b c] last
+        let f = func(vec![p("a")],
+                     Some(sym::Param { opt: true, ..p("rest") }),
+                     vec![grouped("b", 1), grouped("c", 1), p("last")]);
+        assert_eq!(bind(&f, &["1", "x"]), Ok("a=1 rest= b= c= last=x".to_string()));
+        assert_eq!(bind(&f, &["1", "2", "x"]), Ok("a=1 rest=2 b= c= last=x".to_string()));
+        // the group is filled before the vararg
+        assert_eq!(bind(&f, &["1", "2", "3", "x"]), Ok("a=1 rest= b=2 c=3 last=x".to_string()));
+        assert_eq!(bind(&f, &["1", "2", "3", "4", "x"]),
+                   Ok("a=1 rest=2 b=3 c=4 last=x".to_string()));
+    }
+
+    #[test]
+    fn checks_patterns() {
+        let f = func(vec![sym::Param { pat: Some(sym::ParamPat::Int), ..p("n") }],
+                     Some(sym::Param { opt: true, pat: Some(sym::ParamPat::Int), ..p("ns") }),
+                     vec![]);
+        assert_eq!(bind(&f, &["-3", "4"]), Ok("n=-3 ns=4".to_string()));
+        assert_eq!(bind(&f, &["x"]), Err("arg 'n' ('x') is not an int".to_string()));
+        assert_eq!(bind(&f, &["1", "2", "y"]), Err("arg 'ns' ('y') is not an int".to_string()));
+    }
+}
@@ -0,0 +1,191 @@
+extern crate glob;
+
+use std::io::BufReader;
+use std::fs;
+use std::path::PathBuf;
+use std::rc;
+
+use posix;
+
+use exec::Arg;
+use hist::Histvec;
+use shell::Shell;
+
+const USAGE: &str = "history: usage: history [-t] [n] | history [-t] -s pattern | \
+                             history -d n[-m] | history -c | history -w|-r [file] | \
+                             history -e n|-n|prefix";
+
+fn print_entry(sh: &Shell, idx: usize, times: bool) {
+    let e = match sh.ht.hist_entry(idx) {
+        Some(e) => e,
+        None => return,
+    };
+    let mut pre = format!("{:5}  ", sh.ht.hist_len() - idx);
+    if times {
+        let status = e.status.map(|s| s.to_string()).unwrap_or("-".to_string());
+        pre.push_str(&format!("{}  {:>3}  ", posix::local_time(e.time), status));
+    }
+
+    // later lines of an entry line up with its first
+    let indent = " ".repeat(pre.len());
+    for (i, l) in e.line.lines().enumerate() {
+        println!("{}{}", if i == 0 { &pre } else { &indent }, l);
+    }
+}
+
+// parses 'n' or 'n-m' into the indices (newest first) of those entries, in a
+// history len entries long
+fn parse_range(len: usize, range: &str) -> Option<(usize, usize)> {
+    let mut nums = range.splitn(2, '-').map(|n| n.parse::<usize>());
+    let first = match nums.next() {
+        Some(Ok(n)) if n >= 1 && n <= len => n,
+        _ => return None,
+    };
+    let last = match nums.next() {
+        Some(Ok(n)) if n >= first && n <= len => n,
+        Some(_) => return None,
+        None => first,
+    };
+    Some((len - last, len - first))
+}
+
+// finds the entries with pat (a glob) somewhere in them, giving their indices
+// oldest first
+fn search(ht: &Histvec, pat: &str) -> Result<Vec<usize>, glob::PatternError> {
+    let pat = try!(glob::Pattern::new(&format!("*{}*", pat)));
+    Ok((0..ht.hist_len())
+           .rev()
+           .filter(|&i| ht.hist_get(i).map(|l| pat.matches(l)).unwrap_or(false))
+           .collect())
+}
+
+/// `history` lists the history, or with flags searches, edits, saves or
+/// loads it, or re-runs an entry from it.
+pub fn history_main() -> rc::Rc<Fn(Vec<Arg>, &mut Shell, Option<BufReader<fs::File>>) -> i32> {
+    rc::Rc::new(|args: Vec<Arg>, sh: &mut Shell, _in: Option<BufReader<fs::File>>| -> i32 {
+        let mut av = args.into_iter().flat_map(|x| x.into_vec()).collect::<Vec<_>>();
+        let times = av.iter().any(|a| a == "-t");
+        av.retain(|a| a != "-t");
+
+        let flag = if !av.is_empty() && av[0].starts_with('-') {
+            av.remove(0)
+        } else {
+            String::new()
+        };
+
+        match (&flag as &str, av.len()) {
+            ("", 0) | ("", 1) => {
+                let len = sh.ht.hist_len();
+                let n = match av.first().map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) => n,
+                    Some(Err(_)) => {
+                        warn!("{}", USAGE);
+                        return 2;
+                    }
+                    None => len,
+                };
+                for idx in (0..n.min(len)).rev() {
+                    print_entry(sh, idx, times);
+                }
+                0
+            }
+            ("-s", 1) => {
+                let idxs = match search(&sh.ht, &av[0]) {
+                    Ok(idxs) => idxs,
+                    Err(e) => {
+                        warn!("history: Bad pattern: {}", e);
+                        return 2;
+                    }
+                };
+                for &idx in &idxs {
+                    print_entry(sh, idx, times);
+                }
+                if idxs.is_empty() { 1 } else { 0 }
+            }
+            ("-d", 1) => {
+                match parse_range(sh.ht.hist_len(), &av[0]) {
+                    Some((first, last)) => {
+                        for idx in (first..last + 1).rev() {
+                            sh.ht.hist_delete(idx);
+                        }
+                        0
+                    }
+                    None => {
+                        warn!("history: '{}' is not in the history", av[0]);
+                        1
+                    }
+                }
+            }
+            ("-c", 0) => {
+                sh.ht.hist_clear();
+                0
+            }
+            ("-w", 0) | ("-w", 1) | ("-r", 0) | ("-r", 1) => {
+                let path = av.pop().map(PathBuf::from);
+                let res = if flag == "-w" {
+                    sh.ht.hist_write(path)
+                } else {
+                    sh.ht.hist_read(path)
+                };
+                if let Err(e) = res {
+                    warn!("history: {}", e);
+                    return 1;
+                }
+                0
+            }
+            ("-e", 1) => {
+                let line = match sh.ht.hist_lookup(&av[0]) {
+                    Some(l) => l,
+                    None => {
+                        warn!("history: '{}' is not in the history", av[0]);
+                        return 1;
+                    }
+                };
+                println!("{}", line);
+                // what's remembered is what was run, not how it was asked for
+                sh.ht.hist_amend(&line);
+                sh.input_loop(Some(line.lines().map(|l| l.to_string()).collect()), false);
+                sh.status_code
+            }
+            _ => {
+                warn!("{}", USAGE);
+                2
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges() {
+        // numbered from 1, the oldest; indices from 0, the newest
+        assert_eq!(parse_range(5, "1"), Some((4, 4)));
+        assert_eq!(parse_range(5, "5"), Some((0, 0)));
+        assert_eq!(parse_range(5, "2-4"), Some((1, 3)));
+        assert_eq!(parse_range(5, "3-3"), Some((2, 2)));
+        assert_eq!(parse_range(5, "0"), None);
+        assert_eq!(parse_range(5, "6"), None);
+        assert_eq!(parse_range(5, "4-2"), None);
+        assert_eq!(parse_range(5, "2-6"), None);
+        assert_eq!(parse_range(5, "2-"), None);
+        assert_eq!(parse_range(5, "x"), None);
+        assert_eq!(parse_range(0, "1"), None);
+    }
+
+    #[test]
+    fn searches() {
+        let mut ht = Histvec::new();
+        for l in &["make test", "git commit", "git push", "echo [x]"] {
+            ht.push(l, "all", 0);
+        }
+        // oldest first, as listed
+        assert_eq!(search(&ht, "git").unwrap(), [2, 1]);
+        assert_eq!(search(&ht, "t?c").unwrap(), [2]);
+        assert_eq!(search(&ht, "nope").unwrap(), Vec::<usize>::new());
+        assert_eq!(search(&ht, "[[]x]").unwrap(), [0]);
+        assert!(search(&ht, "[x").is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use std::io::BufReader;
+use std::fs;
+use std::rc;
+
+use sym::ScInter;
+use sym::ScType;
+
+use builtins::source::find_file;
+
+use exec::Arg;
+use shell::Shell;
+
+// runs the module's file in its own scope, keeping what it defines.  gives
+// the status the file finished with
+fn load_module(sh: &mut Shell, name: &str) -> i32 {
+    let path = match find_file(sh, &format!("{}.tin", name), "_modpath") {
+        Some(pt) => pt,
+        None => {
+            warn!("import: module '{}' not found", name);
+            return 2;
+        }
+    };
+    let path = fs::canonicalize(&path).unwrap_or(path);
+
+    if sh.srcs.contains(&path) {
+        warn!("import: '{}' is already being loaded", path.display());
+        return 2;
+    }
+
+    sh.st.new_scope_in(ScType::Fn, Some(name.to_string()));
+    let res = sh.file_exec(&path);
+    sh.st.close_module(name);
+
+    let r = match res {
+        Ok(Some(ScInter::Return(x))) => x,
+        Ok(_) => sh.status_code,
+        Err(e) => {
+            warn!("import: {}: {}", path.display(), e);
+            2
+        }
+    };
+    // a module which failed is tried again next time
+    if r != 0 {
+        sh.st.drop_module(name);
+    }
+    r
+}
+
+pub fn import_main() -> rc::Rc<Fn(Vec<Arg>, &mut Shell, Option<BufReader<fs::File>>) -> i32> {
+    rc::Rc::new(|args: Vec<Arg>, sh: &mut Shell, _in: Option<BufReader<fs::File>>| -> i32 {
+        let mut av = args.into_iter().flat_map(|x| x.into_vec()).collect::<Vec<String>>();
+        if av.is_empty() {
+            warn!("import: module name required");
+            return 2;
+        }
+
+        let name = av.remove(0);
+        if name.is_empty() || name.contains('/') || name.contains('.') {
+            warn!("import: invalid module name '{}'", name);
+            return 2;
+        }
+
+        // modules are only ever run once
+        if !sh.st.has_module(&name) {
+            let r = load_module(sh, &name);
+            if r != 0 {
+                return r;
+            }
+        }
+
+        // anything listed is also made available without the 'name.' prefix
+        let missing = sh.st.import_syms(&name, &av);
+        for k in &missing {
+            warn!("import: module '{}' has no '{}'", name, k);
+        }
+
+        if missing.is_empty() {
+            0
+        } else {
+            1
+        }
+    })
+}
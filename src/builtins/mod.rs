@@ -1,4 +1,13 @@
 mod set;
+mod read;
+mod source;
+mod import;
+mod trap;
+mod exec;
+mod command;
+mod history;
+mod bind;
+mod fnargs;
 
 use std::collections::HashMap;
 use std::process::exit;
@@ -7,17 +16,20 @@ use std::fs;
 use std::env;
 use std::mem;
 
-use std::io;
 use std::io::BufReader;
-use std::io::BufRead;
 
 use sym;
+use opts;
 
 use sym::ScopeSpec;
 use sym::ScType;
 use sym::ScInter;
 use exec::Arg;
 use shell::Shell;
+use shell::Frame;
+
+// how many frames of a stack trace to print when the max depth is hit
+const TRACE_LEN: usize = 10;
 
 #[derive(Clone)]
 pub struct Builtin {
@@ -31,7 +43,6 @@ pub struct Builtin {
 
 // TODO:
 //  - elementary flow control
-//  - hash
 //  - __blank
 //  - __fn_exec
 
@@ -60,6 +71,16 @@ fn blank_builtin() -> Builtin {
     }
 }
 
+/// Resolves what to run in place of a command which can't be found: a
+/// `_command_not_found` fn if there is one, or else the fallback builtin.
+/// It's given the command's name followed by its args.
+pub fn not_found_handler(sh: &mut Shell) -> Builtin {
+    match sh.st.resolve_exec("_command_not_found") {
+        Some(sym::SymE::Fn(f)) => fn_builtin(*f),
+        _ => sh.st.resolve_builtin("_command_not_found").unwrap(),
+    }
+}
+
 pub fn fn_builtin(f: sym::Fn) -> Builtin {
     Builtin {
         name: "__fn_exec",
@@ -67,83 +88,72 @@ pub fn fn_builtin(f: sym::Fn) -> Builtin {
         rd_cap: false,
         bl_cap: true,
         pat_cap: true,
-        run: rc::Rc::new(move |mut args: Vec<Arg>,
+        run: rc::Rc::new(move |args: Vec<Arg>,
                                sh: &mut Shell,
                                _in: Option<BufReader<fs::File>>|
                                -> i32 {
 
-            let lines = f.lines.clone();
-            if !f.inline {
-                sh.st.new_scope(ScType::Fn);
-            }
-
-            // parse/figure out the args
-            // TODO: [] params
-            for an in &f.args {
-                let mut an = an as &str;
-                let mut opt = false;
-                if an.ends_with("?") {
-                    opt = true;
-                    an = &an[..an.len() - 1];
+            let mut av = args.into_iter().map(|x| x.into_string()).collect::<Vec<String>>();
+            let binds = match fnargs::parse_flags(&f, &mut av) {
+                Ok(Some(mut b)) => {
+                    fnargs::bind_args(&f, av).map(|x| {
+                        b.extend(x);
+                        b
+                    })
+                }
+                Ok(None) => {
+                    fnargs::print_help(&f);
+                    return 0;
+                }
+                Err(e) => Err(e),
+            };
+            let binds = match binds {
+                Ok(b) => b,
+                Err(e) => {
+                    warn!("fn '{}': {}", f.name, e);
+                    return 2;
                 }
-                if args.len() > 0 {
-                    let _ = sh.st.set_scope(&an, args.remove(0).into_string(), ScopeSpec::Local);
+            };
+
+            // give up on the whole lot rather than let the stack overflow
+            let max = opts::get("__tin_max_depth").and_then(|x| x.parse::<usize>().ok()).unwrap_or(0);
+            if max > 0 && sh.calls.len() >= max {
+                warn!("fn '{}': max call depth ({}) exceeded", f.name, max);
+                let trace = sh.stack_trace();
+                if trace.len() > TRACE_LEN {
+                    // both ends are worth seeing: where it went wrong and where it started
+                    let half = TRACE_LEN / 2;
+                    for l in &trace[..half] {
+                        eprintln!("{}", l);
+                    }
+                    eprintln!("  ... ({} frames elided)", trace.len() - 2 * half);
+                    for l in &trace[trace.len() - half..] {
+                        eprintln!("{}", l);
+                    }
                 } else {
-                    if !opt {
-                        warn!("fn '{}': Not enough args provided", f.name);
-                        return 2;  // TODO: care about this more
-                    } else {
-                        let _ = sh.st.set_scope(&an, "".to_string(), ScopeSpec::Local);
+                    for l in &trace {
+                        eprintln!("{}", l);
                     }
                 }
+                sh.st.sc_abort();
+                return 2;
             }
 
-            if let &Some(ref va) = &f.vararg {
-                let mut va = va as &str;
-                if let &Some(ref pa) = &f.postargs {
-                    for a in pa.iter().rev() {
-                        let mut a = a as &str;
-                        let mut opt = false;
-                        if a.ends_with("?") {
-                            opt = true;
-                            a = &a[..a.len() - 1];
-                        }
-                        match args.pop() {
-                            Some(s) => {
-                                let _ = sh.st
-                                    .set_scope(a, s.clone().into_string(), ScopeSpec::Local);
-                            }
-                            None => {
-                                if !opt {
-                                    warn!("fn '{}': Not enough args provided", f.name);
-                                    return 2;
-                                } else {
-                                    let _ = sh.st.set_scope(&a, "".to_string(), ScopeSpec::Local);
-                                }
-                            }
-                        }
-                    }
-                }
+            sh.calls.push(Frame {
+                name: f.name.clone(),
+                file: mem::replace(&mut sh.file, f.file.clone()),
+                line: mem::replace(&mut sh.lineno, f.line),
+            });
 
-                let mut opt = false;
-                if va.ends_with("?") {
-                    opt = true;
-                    va = &va[..va.len() - 1];
-                }
-                if args.len() > 0 {
-                    let s =
-                        args.drain(..).map(|x| x.into_string()).collect::<Vec<String>>().join(" ");
-                    let _ = sh.st.set_scope(&va, s, ScopeSpec::Local);
-                } else {
-                    if !opt {
-                        warn!("fn '{}': Not enough args provided", f.name);
-                        return 2;
-                    } else {
-                        let _ = sh.st.set_scope(&va, "".to_string(), ScopeSpec::Local);
-                    }
-                }
+            let lines = f.lines.clone();
+            if !f.inline {
+                sh.st.push_closure(&f.env);
+                sh.st.new_scope_in(ScType::Fn, f.module.clone());
             }
 
+            for (k, v) in binds {
+                let _ = sh.st.set_scope(&k, v, ScopeSpec::Local);
+            }
 
             let c = sh.input_loop(Some(lines), false);
             if let Some(ScInter::Return(x)) = c {
@@ -151,9 +161,15 @@ pub fn fn_builtin(f: sym::Fn) -> Builtin {
             }
 
             if !f.inline {
+                sh.ret_val = sh.st.pull_ret_val();
                 sh.st.del_scope();
+                sh.st.pop_closure(&f.env);
             }
 
+            let fr = sh.calls.pop().unwrap();
+            sh.file = fr.file;
+            sh.lineno = fr.line;
+
             sh.status_code
         }),
     }
@@ -313,6 +329,25 @@ impl Builtin {
                           }),
                       });
 
+        bi_map.insert("_command_not_found",
+                      Builtin {
+                          name: "_command_not_found",
+                          desc: "The default (fallback) handler for commands which can't be found",
+                          rd_cap: false,
+                          bl_cap: false,
+                          pat_cap: false,
+                          run: rc::Rc::new(|args: Vec<Arg>,
+                                            sh: &mut Shell,
+                                            _in: Option<BufReader<fs::File>>|
+                                            -> i32 {
+                              if !sh.st.subsh {
+                                  let cmd = args.into_iter().next().map(|a| a.into_string());
+                                  warn!("Command '{}' not found.", cmd.unwrap_or_default());
+                              }
+                              127
+                          }),
+                      });
+
 
         bi_map.insert("loop",
                       Builtin {
@@ -412,19 +447,26 @@ impl Builtin {
                                             sh: &mut Shell,
                                             _in: Option<BufReader<fs::File>>|
                                             -> i32 {
+                let mut args = args.into_iter().map(|x| x.into_string()).collect::<Vec<_>>();
+
+                // 'return -v value' gives the fn a value, as well as a status
+                let val = if !args.is_empty() && args[0] == "-v" {
+                    if args.len() < 2 {
+                        warn!("return: -v requires a value");
+                        return 2;
+                    }
+                    args.remove(0);
+                    Some(args.remove(0))
+                } else {
+                    None
+                };
+
                 let ret_code = if args.len() == 0 {
                     0
                 } else {
-                    if let Arg::Str(ref s) = args[0] {
-                        match s.parse::<i32>() {
-                            Ok(i) => i,
-                            Err(_) => 2,
-                        }
-                    } else {
-                        123  // should never happen?
-                    }
+                    args[0].parse::<i32>().unwrap_or(2)
                 };
-                sh.st.sc_return(ret_code);
+                sh.st.sc_return(ret_code, val);
 
                 12  // this return code is only used on failure
             }),
@@ -442,6 +484,16 @@ impl Builtin {
                           run: set::set_main(), // set is big, yeah yeah yeah
                       });
 
+        bi_map.insert("fn",
+                      Builtin {
+                          name: "fn",
+                          desc: "Get a fn as a value, to be passed around and called later",
+                          rd_cap: false,
+                          bl_cap: true,
+                          pat_cap: false,
+                          run: set::fn_main(),
+                      });
+
         bi_map.insert("cd",
                       Builtin {
                           name: "cd",
@@ -508,25 +560,29 @@ impl Builtin {
                           bl_cap: false,
                           pat_cap: false,
                           run: rc::Rc::new(|args: Vec<Arg>,
-                                            _sh: &mut Shell,
+                                            sh: &mut Shell,
                                             _in: Option<BufReader<fs::File>>|
                                             -> i32 {
-                if args.len() == 0 {
-                    exit(0);
-                }
-
-                // will always be true
-                if let Arg::Str(ref a) = args[0] {
+                let code = if args.is_empty() {
+                    0
+                } else if let Arg::Str(ref a) = args[0] {
+                    // will always be a Str
                     match a.parse::<i32>() {
-                        Ok(i) => exit(i),
+                        Ok(i) => i,
                         Err(_) => {
                             warn!("exit: numeric argument required.");
-                            exit(2)
+                            2
                         }
                     }
                 } else {
                     unreachable!();
-                }
+                };
+
+                sh.status_code = code;
+                // exit's own history entry never gets back to the input loop
+                sh.ht.hist_finish(code);
+                sh.exit_trap();
+                exit(code);
             }),
                       });
 
@@ -537,40 +593,127 @@ impl Builtin {
                           rd_cap: false,
                           bl_cap: false,
                           pat_cap: false,
-                          run: rc::Rc::new(|_args: Vec<Arg>,
-                                            sh: &mut Shell,
-                                            _in: Option<BufReader<fs::File>>|
-                                            -> i32 {
-                              sh.ht.hist_print();
-                              0
-                          }),
+                          run: history::history_main(),
+                      });
+
+        bi_map.insert("bind",
+                      Builtin {
+                          name: "bind",
+                          desc: "List/change key bindings at the prompt",
+                          rd_cap: false,
+                          bl_cap: false,
+                          pat_cap: false,
+                          run: bind::bind_main(),
+                      });
+
+        bi_map.insert("source",
+                      Builtin {
+                          name: "source",
+                          desc: "Run a file's commands in the current shell",
+                          rd_cap: false,
+                          bl_cap: false,
+                          pat_cap: false,
+                          run: source::source_main(),
+                      });
+
+        bi_map.insert(".",
+                      Builtin {
+                          name: ".",
+                          desc: "Run a file's commands in the current shell",
+                          rd_cap: false,
+                          bl_cap: false,
+                          pat_cap: false,
+                          run: source::source_main(),
+                      });
+
+        bi_map.insert("import",
+                      Builtin {
+                          name: "import",
+                          desc: "Load a module once, making its contents available as module.name",
+                          rd_cap: false,
+                          bl_cap: false,
+                          pat_cap: false,
+                          run: import::import_main(),
+                      });
+
+        bi_map.insert("command",
+                      Builtin {
+                          name: "command",
+                          desc: "Run a builtin or binary, skipping fns",
+                          rd_cap: false,
+                          bl_cap: true,
+                          pat_cap: true,
+                          run: command::command_main(),
+                      });
+
+        bi_map.insert("builtin",
+                      Builtin {
+                          name: "builtin",
+                          desc: "Run a builtin, skipping fns and binaries",
+                          rd_cap: false,
+                          bl_cap: true,
+                          pat_cap: true,
+                          run: command::builtin_main(),
+                      });
+
+        bi_map.insert("type",
+                      Builtin {
+                          name: "type",
+                          desc: "Tell what a name would be run as",
+                          rd_cap: false,
+                          bl_cap: false,
+                          pat_cap: false,
+                          run: command::type_main(),
+                      });
+
+        bi_map.insert("which",
+                      Builtin {
+                          name: "which",
+                          desc: "Tell what a name would be run as, first match only",
+                          rd_cap: false,
+                          bl_cap: false,
+                          pat_cap: false,
+                          run: command::which_main(),
+                      });
+
+        bi_map.insert("hash",
+                      Builtin {
+                          name: "hash",
+                          desc: "List, add or forget remembered binary paths",
+                          rd_cap: false,
+                          bl_cap: false,
+                          pat_cap: false,
+                          run: command::hash_main(),
+                      });
+
+        bi_map.insert("exec",
+                      Builtin {
+                          name: "exec",
+                          desc: "Replace the shell with a command, or redirect the shell itself",
+                          rd_cap: true,
+                          bl_cap: false,
+                          pat_cap: false,
+                          run: exec::exec_main(),
+                      });
+
+        bi_map.insert("trap",
+                      Builtin {
+                          name: "trap",
+                          desc: "Run a block when the shell gets a signal, exits or sees a command fail",
+                          rd_cap: false,
+                          bl_cap: true,
+                          pat_cap: false,
+                          run: trap::trap_main(),
                       });
 
-        // TODO: this shouldn't even... be a real builtin? maybe?
         bi_map.insert("read",
                       Builtin {
                           name: "read",
-                          desc: "Read from stdin or a file and echo to stdout",
+                          desc: "Read a line from stdin into variables, or echo it to stdout",
                           rd_cap: false,
                           bl_cap: false,
                           pat_cap: false,
-                          run: rc::Rc::new(|_args: Vec<Arg>,
-                                            _sh: &mut Shell,
-                                            inp: Option<BufReader<fs::File>>|
-                                            -> i32 {
-                let mut in_buf = String::new();
-                let res = if let Some(mut br) = inp {
-                    br.read_line(&mut in_buf)
-                } else {
-                    io::stdin().read_line(&mut in_buf)
-                };
-                if res.is_ok() {
-                    print!("{}", in_buf);
-                    0
-                } else {
-                    2
-                }
-            }),
+                          run: read::read_main(),
                       });
 
         bi_map
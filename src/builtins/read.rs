@@ -0,0 +1,278 @@
+use std::cmp;
+use std::io;
+use std::io::BufReader;
+use std::io::Write;
+use std::fs;
+use std::rc;
+use std::str;
+use std::time::{Duration, Instant};
+
+use posix;
+use prompt::TermMode;
+
+use exec::Arg;
+use shell::Shell;
+
+// return codes besides success
+const READ_EOF: i32 = 1;
+const READ_ERR: i32 = 2;
+const READ_TIMEOUT: i32 = 142; // as if we'd gotten SIGALRM
+
+struct ReadOpts {
+    prompt: Option<String>,
+    count: Option<usize>,
+    delim: char,
+    raw: bool,
+    silent: bool,
+    timeout: Option<Duration>,
+}
+
+enum ReadEnd {
+    Delim,
+    Count,
+    Eof,
+    Timeout,
+}
+
+// takes the value for an option like '-p', which may be attached ('-d,') or the next arg
+fn opt_val(flag: char, rest: &str, av: &mut Vec<Arg>) -> Option<String> {
+    if !rest.is_empty() {
+        Some(rest.to_string())
+    } else if !av.is_empty() {
+        Some(av.remove(0).into_string())
+    } else {
+        warn!("read: -{} requires an argument", flag);
+        None
+    }
+}
+
+fn read_opts(av: &mut Vec<Arg>) -> Option<ReadOpts> {
+    let mut ro = ReadOpts {
+        prompt: None,
+        count: None,
+        delim: '\n',
+        raw: false,
+        silent: false,
+        timeout: None,
+    };
+
+    macro_rules! val {
+        ($c:expr, $rest:expr) => (match opt_val($c, $rest, av) {
+            Some(v) => v,
+            None => return None,
+        });
+    }
+
+    while !av.is_empty() && av[0].is_str() && av[0].as_str().starts_with('-') {
+        let s = av.remove(0).unwrap_str();
+        if s == "--" {
+            break;
+        }
+
+        for (i, c) in s.char_indices().skip(1) {
+            let rest = &s[i + c.len_utf8()..];
+            match c {
+                'r' => ro.raw = true,
+                's' => ro.silent = true,
+                'p' => {
+                    ro.prompt = Some(val!(c, rest));
+                    break;
+                }
+                'd' => {
+                    // an empty delimiter means NUL, as in other shells
+                    ro.delim = val!(c, rest).chars().next().unwrap_or('\0');
+                    break;
+                }
+                'n' => {
+                    match val!(c, rest).parse::<usize>() {
+                        Ok(n) => ro.count = Some(n),
+                        Err(_) => {
+                            warn!("read: -n requires a count");
+                            return None;
+                        }
+                    }
+                    break;
+                }
+                't' => {
+                    match val!(c, rest).parse::<f64>() {
+                        Ok(t) if t >= 0.0 => {
+                            ro.timeout = Some(Duration::from_millis((t * 1000.0) as u64))
+                        }
+                        _ => {
+                            warn!("read: -t requires a number of seconds");
+                            return None;
+                        }
+                    }
+                    break;
+                }
+                _ => {
+                    warn!("read: Unrecognized argument '{}' found.", c);
+                    return None;
+                }
+            }
+        }
+    }
+
+    Some(ro)
+}
+
+// Reads from stdin a byte at a time: stdin may be shared with whatever runs
+// after us, so we can't read past what we were asked for.
+fn read_input(ro: &ReadOpts) -> io::Result<(String, ReadEnd)> {
+    // a timeout too far off to reach is no timeout at all
+    let deadline = ro.timeout.and_then(|t| Instant::now().checked_add(t));
+    let mut delim_buf = [0; 4];
+    let delim = ro.delim.encode_utf8(&mut delim_buf).as_bytes();
+
+    let mut buf: Vec<u8> = Vec::new();
+    let mut bs = false;
+    let mut b = [0; 1];
+
+    let end = loop {
+        if let Some(count) = ro.count {
+            if str::from_utf8(&buf).map(|s| s.chars().count() >= count).unwrap_or(false) {
+                break ReadEnd::Count;
+            }
+        }
+
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            let left = if deadline > now {
+                deadline - now
+            } else {
+                Duration::from_millis(0)
+            };
+            let ms = cmp::min(left.as_millis(), i32::MAX as u128) as i32;
+            match posix::poll_read(0, ms) {
+                Ok(true) => {}
+                Ok(false) => break ReadEnd::Timeout,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        match posix::read_fd(0, &mut b) {
+            Ok(0) => break ReadEnd::Eof,
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+
+        if bs {
+            bs = false;
+            // backslash-newline continues the line
+            if b[0] != b'\n' {
+                buf.push(b[0]);
+            }
+            continue;
+        } else if b[0] == b'\\' && !ro.raw {
+            bs = true;
+            continue;
+        }
+
+        buf.push(b[0]);
+        if buf.ends_with(delim) {
+            let len = buf.len() - delim.len();
+            buf.truncate(len);
+            break ReadEnd::Delim;
+        }
+    };
+
+    Ok((String::from_utf8_lossy(&buf).into_owned(), end))
+}
+
+// assigns the whitespace-separated fields of line to vars; the last var gets
+// whatever is left over.
+fn assign_fields(sh: &mut Shell, vars: Vec<String>, line: &str) -> i32 {
+    let mut rest = line.trim();
+    let len = vars.len();
+    let mut r = 0;
+
+    for (i, k) in vars.iter().enumerate() {
+        let val = if i == len - 1 {
+            rest
+        } else {
+            match rest.find(char::is_whitespace) {
+                Some(n) => {
+                    let val = &rest[..n];
+                    rest = rest[n..].trim_start();
+                    val
+                }
+                None => {
+                    let val = rest;
+                    rest = "";
+                    val
+                }
+            }
+        };
+
+        if sh.st.set(k, val.to_string()).is_err() {
+            r = READ_ERR;
+        }
+    }
+
+    r
+}
+
+pub fn read_main() -> rc::Rc<Fn(Vec<Arg>, &mut Shell, Option<BufReader<fs::File>>) -> i32> {
+    rc::Rc::new(|mut args: Vec<Arg>, sh: &mut Shell, _in: Option<BufReader<fs::File>>| -> i32 {
+        let ro = match read_opts(&mut args) {
+            Some(ro) => ro,
+            None => return READ_ERR,
+        };
+        let vars = args.drain(..).flat_map(|x| x.into_vec()).collect::<Vec<String>>();
+
+        // only bother the user with a prompt if there's a user to bother
+        let tty = posix::is_interactive();
+        if let Some(ref p) = ro.prompt {
+            if tty {
+                eprint!("{}", p);
+                let _ = io::stderr().flush();
+            }
+        }
+
+        let (line, end) = {
+            let _tm = if tty && (ro.silent || ro.count.is_some()) {
+                match TermMode::new(0, !ro.silent, ro.count.is_none()) {
+                    Ok(tm) => Some(tm),
+                    Err(e) => {
+                        warn!("read: Could not set terminal mode: {}", e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            match read_input(&ro) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("read: {}", e);
+                    return READ_ERR;
+                }
+            }
+        };
+
+        if ro.silent && tty {
+            // the user's newline wasn't echoed
+            eprintln!();
+        }
+
+        let r = if vars.is_empty() {
+            // with nowhere to put it, just echo the input
+            match end {
+                ReadEnd::Eof if line.is_empty() => {}
+                _ => println!("{}", line),
+            }
+            0
+        } else {
+            assign_fields(sh, vars, &line)
+        };
+
+        match end {
+            ReadEnd::Eof => READ_EOF,
+            ReadEnd::Timeout => READ_TIMEOUT,
+            ReadEnd::Delim | ReadEnd::Count => r,
+        }
+    })
+}
@@ -2,14 +2,46 @@ use std::io::BufReader;
 use std::fs;
 use std::rc;
 
+extern crate regex;
+use self::regex::Regex;
+
 use sym;
+use exec;
+use posix;
 
 use exec::Arg;
 use exec::Redir;
 use shell::Shell;
 
-fn rd_set(_rd: Redir) -> i32 {
-    println!("Redirection set is unimplemented");
+// applies redirects to the shell itself, so that they persist for the rest of the session
+fn rd_set(rds: Vec<Redir>) -> i32 {
+    for rd in &rds {
+        match *rd {
+            Redir::RdArgOut(_) | Redir::RdArgIn(_) | Redir::RdStringIn(..) => {
+                warn!("set: Redirection '{}' cannot be set", Arg::Rd(rd.clone()).into_string());
+                return 2;
+            }
+            _ => {}
+        }
+    }
+
+    match exec::redirect_shell(rds, false) {
+        Ok(_) => 0,
+        Err(e) => {
+            warn!("set: Could not redirect: {}", e);
+            2
+        }
+    }
+}
+
+// lists the shell's open fds and, where we can tell, what they point to
+fn rd_list() -> i32 {
+    for fd in posix::open_fds() {
+        match fs::read_link(format!("/dev/fd/{}", fd)) {
+            Ok(pt) => println!("  {} -> {}", fd, pt.display()),
+            Err(_) => println!("  {}", fd),
+        }
+    }
     0
 }
 
@@ -64,18 +96,127 @@ fn set_keys(av: &mut Vec<Arg>) -> Vec<String> {
     ret
 }
 
-fn fn_set(sh: &mut Shell, kv: Vec<String>, mut av: Vec<Arg>, spec: sym::ScopeSpec) -> i32 {
+// parses a single fn parameter: name, then any of '?' and ':type', then
+// '~regex', and finally '=default'.  the default takes everything after the
+// first '=', so a default may hold a '~' (as in 'dir=~/x') but a regex can't
+// hold an '=' (it can match one as '\x3d')
+fn parse_param(tok: &str, group: Option<usize>) -> Result<sym::Param, String> {
+    let (decl, default) = match tok.find('=') {
+        Some(n) => (&tok[..n], Some(tok[n + 1..].to_string())),
+        None => (tok, None),
+    };
+    let (decl, pat) = match decl.find('~') {
+        Some(n) => (&decl[..n], Some(&decl[n + 1..])),
+        None => (decl, None),
+    };
+    // 'n=5~re' is the regex given after the default: say so, rather than take
+    // it all as the default
+    if let Some(ref d) = default {
+        if pat.is_none() && d.chars().skip(1).any(|c| c == '~') {
+            return Err(format!("parameter '{}' gives its pattern after its default (write 'name~regex=default')",
+                               tok));
+        }
+    }
+
+    let name_end = decl.find(|c| "?:".contains(c)).unwrap_or(decl.len());
+    let mut p = sym::Param {
+        name: decl[..name_end].to_string(),
+        opt: group.is_some() || default.is_some(),
+        group,
+        default,
+        pat: None,
+    };
+    if p.name.is_empty() {
+        return Err(format!("parameter '{}' has no name", tok));
+    }
+
+    let mut rest = &decl[name_end..];
+    while !rest.is_empty() {
+        if rest.starts_with('?') {
+            p.opt = true;
+            rest = &rest[1..];
+        } else {
+            let end = rest[1..].find(|c| "?:".contains(c)).map(|n| n + 1).unwrap_or(rest.len());
+            let val = &rest[1..end];
+            p.pat = Some(match val {
+                "int" => sym::ParamPat::Int,
+                "file" => sym::ParamPat::File,
+                "dir" => sym::ParamPat::Dir,
+                _ => return Err(format!("parameter '{}' has unknown type '{}'", p.name, val)),
+            });
+            rest = &rest[end..];
+        }
+    }
+
+    if let Some(pat) = pat {
+        match Regex::new(pat) {
+            Ok(re) => p.pat = Some(sym::ParamPat::Regex(re)),
+            Err(e) => return Err(format!("parameter '{}' has a bad pattern: {}", p.name, e)),
+        }
+    }
+
+    Ok(p)
+}
+
+// parses a fn flag: one or both of '-c' and '--long', separated by a '/', and
+// then '=' (with an optional default) if the flag takes a value
+fn parse_flag(tok: &str) -> Result<sym::Flag, String> {
+    let (names, val) = match tok.find('=') {
+        Some(n) => (&tok[..n], Some(tok[n + 1..].to_string())),
+        None => (tok, None),
+    };
+
+    let mut fl = sym::Flag {
+        short: None,
+        long: None,
+        val,
+    };
+    for name in names.split('/') {
+        if name.starts_with("--") && name.len() > 2 && fl.long.is_none() {
+            fl.long = Some(name[2..].to_string());
+        } else if name.starts_with('-') && name.chars().count() == 2 && fl.short.is_none() {
+            fl.short = name.chars().nth(1);
+        } else {
+            return Err(format!("bad flag '{}'", tok));
+        }
+    }
+
+    Ok(fl)
+}
+
+// the doc comment of a fn is the comment lines at the top of its block
+fn fn_doc(lines: &[String]) -> Option<String> {
+    let doc = lines.iter()
+        .map(|l| l.trim())
+        .skip_while(|l| l.is_empty())
+        .take_while(|l| l.starts_with('#'))
+        .map(|l| {
+            let l = &l[1..];
+            l.strip_prefix(' ').unwrap_or(l)
+        })
+        .collect::<Vec<_>>();
+
+    if doc.is_empty() {
+        None
+    } else {
+        Some(doc.join("\n"))
+    }
+}
+
+// parses a fn declaration (everything after the 'fn') into a fn with no name,
+// closing over the scopes it's declared in
+fn fn_parse(sh: &mut Shell, mut av: Vec<Arg>) -> Option<sym::Fn> {
     if av.len() == 0 || !av.last().unwrap().is_bl() {
         warn!("fn declaration must contain a block as its last arg.");
-        return 2;
+        return None;
     }
 
     let exec_bl = av.pop().unwrap().unwrap_bl();
 
-    // TODO: patterns in function args!
+    let mut flags: Vec<sym::Flag> = Vec::new();
     let mut args = Vec::new();
     let mut vararg = None;
-    let mut postargs = None;
+    let mut postargs: Option<Vec<sym::Param>> = None;
 
     let mut flat_args = av.drain(..).flat_map(|x| x.into_vec()).collect::<Vec<_>>();
     let inline = if flat_args.len() > 0 && flat_args[0] == "--inline" {
@@ -85,62 +226,173 @@ fn fn_set(sh: &mut Shell, kv: Vec<String>, mut av: Vec<Arg>, spec: sym::ScopeSpe
         false
     };
 
-    for sl in flat_args.windows(2) {
-        let ref elt = sl[0];
-        let ref lookahead = sl[1];
+    let mut group = None;
+    let mut ngroups = 0;
+    let mut toks = flat_args.iter().peekable();
+    while let Some(tok) = toks.next() {
+        let mut tok = tok as &str;
 
-        if lookahead == "..." {
-            if vararg.is_some() {
-                warn!("set: fn can have at most one vararg");
-                return 2;
+        if tok.starts_with('-') && tok != "..." {
+            if group.is_some() {
+                warn!("set: fn flags cannot be in a parameter group");
+                return None;
             }
-            vararg = Some(elt.to_owned());
-            postargs = Some(Vec::new());
-        } else if elt != "..." {
-            if let Some(ref mut x) = postargs {
-                x.push(elt.to_owned());
-            } else {
-                args.push(elt.to_owned());
+            let fl = match parse_flag(tok) {
+                Ok(fl) => fl,
+                Err(e) => {
+                    warn!("set: {}", e);
+                    return None;
+                }
+            };
+            if flags.iter().any(|x| x.var() == fl.var() || (x.short.is_some() && x.short == fl.short)) {
+                warn!("set: fn flag '{}' declared twice", tok);
+                return None;
             }
+            flags.push(fl);
+            continue;
         }
-    }
 
-    // last arg
-    if let Some(last) = flat_args.last() {
-        if last != "..." {
-            if let Some(ref mut x) = postargs {
-                x.push(last.to_owned());
+        if tok.starts_with('[') {
+            if group.is_some() {
+                warn!("set: fn parameter groups cannot be nested");
+                return None;
+            }
+            ngroups += 1;
+            group = Some(ngroups);
+            tok = &tok[1..];
+        }
+        let closes = group.is_some() && tok.ends_with(']');
+        if closes {
+            tok = &tok[..tok.len() - 1];
+        }
+
+        // varargs may be written 'name...' or 'name ...'
+        let is_va = if tok.ends_with("...") {
+            tok = &tok[..tok.len() - 3];
+            true
+        } else if toks.peek().map(|x| x as &str) == Some("...") {
+            toks.next();
+            true
+        } else {
+            false
+        };
+
+        if !tok.is_empty() {
+            let p = match parse_param(tok, group) {
+                Ok(p) => p,
+                Err(e) => {
+                    warn!("set: {}", e);
+                    return None;
+                }
+            };
+
+            if is_va {
+                if vararg.is_some() {
+                    warn!("set: fn can have at most one vararg");
+                    return None;
+                } else if group.is_some() {
+                    warn!("set: fn vararg cannot be in a parameter group");
+                    return None;
+                }
+                vararg = Some(p);
+                postargs = Some(Vec::new());
+            } else if let Some(ref mut x) = postargs {
+                x.push(p);
             } else {
-                args.push(last.to_owned());
+                args.push(p);
             }
+        } else if is_va {
+            warn!("set: '...' must follow a parameter name");
+            return None;
+        }
+
+        if closes {
+            group = None;
         }
     }
 
+    if group.is_some() {
+        warn!("set: unclosed fn parameter group");
+        return None;
+    }
+
+    Some(sym::Fn {
+        name: String::new(),
+        inline,
+        flags,
+        args,
+        vararg,
+        postargs,
+        doc: fn_doc(&exec_bl),
+        lines: exec_bl,
+        module: sh.st.cur_module(),
+        file: sh.file.clone(),
+        line: sh.lineno,
+        // inline fns just run in whatever scope they're called from
+        env: if inline {
+            sym::Closure::default()
+        } else {
+            sh.st.capture()
+        },
+    })
+}
+
+fn fn_set(sh: &mut Shell, kv: Vec<String>, av: Vec<Arg>, spec: sym::ScopeSpec) -> i32 {
+    let f = match fn_parse(sh, av) {
+        Some(f) => f,
+        None => return 2,
+    };
+
     for k in &kv {
-        sh.st.set_fn(k,
-                     sym::Fn {
-                         name: k.clone(),
-                         inline: inline,
-                         args: args.clone(),
-                         vararg: vararg.clone(),
-                         postargs: postargs.clone(),
-                         lines: exec_bl.clone(),
-                     },
-                     spec);
+        let mut f = f.clone();
+        f.name = k.clone();
+        sh.st.set_fn(k, f, spec);
     }
 
     0
 }
 
+/// `fn name` prints a handle for the fn name, by which it can be passed around
+/// as a value; `fn args... { block }` does the same for a fn with no name.
+pub fn fn_main() -> rc::Rc<Fn(Vec<Arg>, &mut Shell, Option<BufReader<fs::File>>) -> i32> {
+    rc::Rc::new(|args: Vec<Arg>, sh: &mut Shell, _in: Option<BufReader<fs::File>>| -> i32 {
+        let f = if args.len() == 1 && args[0].is_str() {
+            let name = args[0].as_str().to_string();
+            match sh.st.resolve_fn(&name) {
+                Some(f) => f,
+                None => {
+                    warn!("fn: '{}' is not a fn", name);
+                    return 2;
+                }
+            }
+        } else {
+            match fn_parse(sh, args) {
+                Some(f) => f,
+                None => return 2,
+            }
+        };
+
+        println!("{}", sh.st.fn_handle(f));
+        0
+    })
+}
+
 pub fn set_main() -> rc::Rc<Fn(Vec<Arg>, &mut Shell, Option<BufReader<fs::File>>) -> i32> {
     rc::Rc::new(|mut args: Vec<Arg>, sh: &mut Shell, _in: Option<BufReader<fs::File>>| -> i32 {
         // rd-set
-        if args.len() == 1 {
-            if args[0].is_rd() {
-                let rd = args.remove(0).unwrap_rd();
-                return rd_set(rd);
+        if !args.is_empty() && args[0].is_str() && args[0].as_str() == "-r" {
+            args.remove(0);
+            if args.is_empty() {
+                return rd_list();
+            } else if !args.iter().all(|a| a.is_rd()) {
+                warn!("set: -r takes only redirections");
+                return 2;
             }
         }
+        if !args.is_empty() && args.iter().all(|a| a.is_rd()) {
+            let rds = args.drain(..).map(|a| a.unwrap_rd()).collect();
+            return rd_set(rds);
+        }
 
         // get args and keys
         let spec = set_spec(&mut args);
@@ -183,3 +435,52 @@ pub fn set_main() -> rc::Rc<Fn(Vec<Arg>, &mut Shell, Option<BufReader<fs::File>>
         r
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regex(p: &sym::Param) -> Option<&str> {
+        match p.pat {
+            Some(sym::ParamPat::Regex(ref re)) => Some(re.as_str()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parses_params() {
+        let p = parse_param("x?", None).unwrap();
+        assert!(p.opt && p.default.is_none() && p.pat.is_none());
+
+        let p = parse_param("n:int=5", None).unwrap();
+        assert!(p.opt && p.default == Some("5".to_string()));
+        assert!(matches!(p.pat, Some(sym::ParamPat::Int)));
+
+        let p = parse_param("b", Some(1)).unwrap();
+        assert!(p.opt && p.group == Some(1));
+
+        assert!(parse_param("=5", None).is_err());
+        assert!(parse_param("x:num", None).is_err());
+        assert!(parse_param("x~(", None).is_err());
+    }
+
+    #[test]
+    fn parses_patterns_before_defaults() {
+        // a default may hold a '~'
+        let p = parse_param("dir=~/x", None).unwrap();
+        assert_eq!(p.default, Some("~/x".to_string()));
+        assert!(p.pat.is_none());
+
+        let p = parse_param("n~^[1-9]=5", None).unwrap();
+        assert_eq!((&p.name as &str, regex(&p)), ("n", Some("^[1-9]")));
+        assert_eq!(p.default, Some("5".to_string()));
+
+        let p = parse_param("w~^a\\x3db$=a=b", None).unwrap();
+        assert_eq!(regex(&p), Some("^a\\x3db$"));
+        assert_eq!(p.default, Some("a=b".to_string()));
+
+        // the pattern after the default is refused, not taken as the default
+        assert!(parse_param("n=5~^[0-9]+$", None).is_err());
+        assert!(parse_param("n~^[0-9]+$=5~x", None).is_ok());
+    }
+}
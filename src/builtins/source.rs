@@ -0,0 +1,92 @@
+use std::io::BufReader;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc;
+
+use sym;
+use sym::ScType;
+use sym::ScInter;
+use sym::ScopeSpec;
+
+use exec::Arg;
+use shell::Shell;
+
+/// Finds a file to be loaded by name.  Names containing a '/' are taken as-is;
+/// otherwise we look through the colon-separated directories in the variable
+/// path_var, and then the current directory.
+pub fn find_file(sh: &Shell, name: &str, path_var: &str) -> Option<PathBuf> {
+    if !name.contains('/') {
+        match sh.st.resolve_varish(path_var) {
+            Some(sym::SymV::Var(p)) |
+            Some(sym::SymV::Environment(p)) => {
+                for dir in p.split(':').filter(|d| !d.is_empty()) {
+                    let pt = Path::new(dir).join(name);
+                    if pt.is_file() {
+                        return Some(pt);
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+
+    let pt = PathBuf::from(name);
+    if pt.is_file() {
+        Some(pt)
+    } else {
+        None
+    }
+}
+
+// sets the positional args _n, _*, _1, _2, ... in the current scope
+fn set_positional(sh: &mut Shell, av: Vec<String>) {
+    let _ = sh.st.set_scope("_n", av.len().to_string(), ScopeSpec::Local);
+    let _ = sh.st.set_scope("_*", av.join(" "), ScopeSpec::Local);
+    for (i, a) in av.into_iter().enumerate() {
+        let _ = sh.st.set_scope(&format!("_{}", i + 1), a, ScopeSpec::Local);
+    }
+}
+
+pub fn source_main() -> rc::Rc<Fn(Vec<Arg>, &mut Shell, Option<BufReader<fs::File>>) -> i32> {
+    rc::Rc::new(|mut args: Vec<Arg>, sh: &mut Shell, _in: Option<BufReader<fs::File>>| -> i32 {
+        if args.is_empty() {
+            warn!("source: filename argument required");
+            return 2;
+        }
+
+        let name = args.remove(0).into_string();
+        let path = match find_file(sh, &name, "_srcpath") {
+            Some(pt) => pt,
+            None => {
+                warn!("source: '{}' not found", name);
+                return 2;
+            }
+        };
+        let path = fs::canonicalize(&path).unwrap_or(path);
+
+        if sh.srcs.contains(&path) {
+            warn!("source: '{}' is already being sourced", path.display());
+            return 2;
+        }
+
+        // with no args given, the file sees the caller's positional args
+        sh.st.new_scope(ScType::Source);
+        if !args.is_empty() {
+            let av = args.drain(..).flat_map(|x| x.into_vec()).collect();
+            set_positional(sh, av);
+        }
+
+        let res = sh.file_exec(&path);
+        sh.ret_val = sh.st.pull_ret_val();
+        sh.st.del_scope();
+
+        match res {
+            Ok(Some(ScInter::Return(x))) => x,
+            Ok(_) => sh.status_code,
+            Err(e) => {
+                warn!("source: {}: {}", path.display(), e);
+                2
+            }
+        }
+    })
+}
@@ -0,0 +1,128 @@
+use std::io::BufReader;
+use std::fs;
+use std::rc;
+
+use posix;
+use posix::SigAction;
+use opts;
+
+use exec::Arg;
+use shell::Shell;
+
+// the canonical name of a trap: a signal name, or 'EXIT' or 'ERR'
+fn trap_name(name: &str) -> Option<String> {
+    match &name.to_uppercase() as &str {
+        "EXIT" | "0" => Some("EXIT".to_string()),
+        "ERR" => Some("ERR".to_string()),
+        _ => posix::signal_num(name).and_then(posix::signal_name).map(|n| n.to_string()),
+    }
+}
+
+fn print_trap(name: &str, lines: &[String]) {
+    let lines = lines.iter().filter(|l| !l.trim().is_empty()).collect::<Vec<_>>();
+    match lines.len() {
+        0 => println!("trap {{}} {}", name),
+        1 => println!("trap {{ {} }} {}", lines[0].trim(), name),
+        _ => {
+            println!("trap {{");
+            for l in lines {
+                println!("    {}", l.trim());
+            }
+            println!("}} {}", name);
+        }
+    }
+}
+
+// sets (or with no lines, resets) the trap for name
+fn set_trap(sh: &mut Shell, name: &str, lines: Option<Vec<String>>) -> bool {
+    if let Some(sig) = posix::signal_num(name) {
+        let res = match lines {
+            // an empty block means to ignore the signal altogether
+            Some(ref l) if l.iter().all(|x| x.trim().is_empty()) => {
+                posix::set_signal_action(sig, SigAction::Ignore)
+            }
+            Some(_) => posix::set_signal_action(sig, SigAction::Catch),
+            None => posix::reset_signal(sig, opts::is_set("__tin_inter")),
+        };
+        if let Err(e) = res {
+            warn!("trap: Could not set trap for {}: {}", name, e);
+            return false;
+        }
+    }
+
+    match lines {
+        Some(l) => sh.traps.insert(name.to_string(), l),
+        None => sh.traps.remove(name),
+    };
+    true
+}
+
+/// `trap { block } names...` runs the block when any of the named signals is
+/// caught, or for 'EXIT' when the shell exits and for 'ERR' when a command
+/// fails.  `trap - names...` resets them; `trap` and `trap -p names...` list them.
+///
+/// Only commands run at the top level trip ERR: those inside a fn don't, though
+/// the call itself does if it fails.  Children forked off for a pipeline drop
+/// the traps, so EXIT runs once, in the shell itself.
+pub fn trap_main() -> rc::Rc<Fn(Vec<Arg>, &mut Shell, Option<BufReader<fs::File>>) -> i32> {
+    rc::Rc::new(|mut args: Vec<Arg>, sh: &mut Shell, _in: Option<BufReader<fs::File>>| -> i32 {
+        if args.len() == 1 && args[0].is_str() && args[0].as_str() == "-p" {
+            args.clear();
+        }
+        if args.is_empty() {
+            let mut names = sh.traps.keys().cloned().collect::<Vec<_>>();
+            names.sort();
+            for n in names {
+                print_trap(&n, &sh.traps[&n]);
+            }
+            return 0;
+        }
+
+        let lines = match args.remove(0) {
+            Arg::Bl(lines) => Some(lines),
+            Arg::Str(ref s) if s == "-" => None,
+            Arg::Str(ref s) if s == "-p" => {
+                let mut r = 0;
+                for a in args.into_iter().flat_map(|x| x.into_vec()) {
+                    match trap_name(&a) {
+                        Some(n) => {
+                            if let Some(lines) = sh.traps.get(&n) {
+                                print_trap(&n, lines);
+                            }
+                        }
+                        None => {
+                            warn!("trap: '{}' is not a signal", a);
+                            r = 1;
+                        }
+                    }
+                }
+                return r;
+            }
+            _ => {
+                warn!("trap: usage: trap {{ block }} names... | trap - names... | trap -p [names...]");
+                return 2;
+            }
+        };
+
+        if args.is_empty() {
+            warn!("trap: signal names required");
+            return 2;
+        }
+
+        let mut r = 0;
+        for a in args.into_iter().flat_map(|x| x.into_vec()) {
+            match trap_name(&a) {
+                Some(n) => {
+                    if !set_trap(sh, &n, lines.clone()) {
+                        r = 1;
+                    }
+                }
+                None => {
+                    warn!("trap: '{}' is not a signal", a);
+                    r = 1;
+                }
+            }
+        }
+        r
+    })
+}
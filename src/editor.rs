@@ -0,0 +1,703 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+
+extern crate unicode_segmentation;
+use self::unicode_segmentation::UnicodeSegmentation;
+
+/// EditCmd: the things keys can be bound to do at the interactive prompt.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EditCmd {
+    SelfInsert,
+    Accept,
+    Complete,
+    BackwardChar,
+    ForwardChar,
+    BackwardWord,
+    ForwardWord,
+    BeginningOfLine,
+    EndOfLine,
+    DeleteChar,
+    DeleteCharOrEof,
+    BackwardDeleteChar,
+    KillLine,
+    BackwardKillLine,
+    UnixWordRubout,
+    KillWord,
+    BackwardKillWord,
+    Yank,
+    YankPop,
+    TransposeChars,
+    Undo,
+    ClearScreen,
+    Abandon,
+    PreviousHistory,
+    NextHistory,
+    ReverseSearch,
+    ForwardSearch,
+    ViCommandMode,
+}
+
+const CMD_NAMES: [(&str, EditCmd); 28] = [("self-insert", EditCmd::SelfInsert),
+                                                  ("accept-line", EditCmd::Accept),
+                                                  ("complete", EditCmd::Complete),
+                                                  ("backward-char", EditCmd::BackwardChar),
+                                                  ("forward-char", EditCmd::ForwardChar),
+                                                  ("backward-word", EditCmd::BackwardWord),
+                                                  ("forward-word", EditCmd::ForwardWord),
+                                                  ("beginning-of-line", EditCmd::BeginningOfLine),
+                                                  ("end-of-line", EditCmd::EndOfLine),
+                                                  ("delete-char", EditCmd::DeleteChar),
+                                                  ("delete-char-or-eof", EditCmd::DeleteCharOrEof),
+                                                  ("backward-delete-char", EditCmd::BackwardDeleteChar),
+                                                  ("kill-line", EditCmd::KillLine),
+                                                  ("backward-kill-line", EditCmd::BackwardKillLine),
+                                                  ("unix-word-rubout", EditCmd::UnixWordRubout),
+                                                  ("kill-word", EditCmd::KillWord),
+                                                  ("backward-kill-word", EditCmd::BackwardKillWord),
+                                                  ("yank", EditCmd::Yank),
+                                                  ("yank-pop", EditCmd::YankPop),
+                                                  ("transpose-chars", EditCmd::TransposeChars),
+                                                  ("undo", EditCmd::Undo),
+                                                  ("clear-screen", EditCmd::ClearScreen),
+                                                  ("abandon-line", EditCmd::Abandon),
+                                                  ("previous-history", EditCmd::PreviousHistory),
+                                                  ("next-history", EditCmd::NextHistory),
+                                                  ("reverse-search-history", EditCmd::ReverseSearch),
+                                                  ("forward-search-history", EditCmd::ForwardSearch),
+                                                  ("vi-command-mode", EditCmd::ViCommandMode)];
+
+impl EditCmd {
+    pub fn from_name(name: &str) -> Option<EditCmd> {
+        CMD_NAMES.iter().find(|&&(n, _)| n == name).map(|&(_, c)| c)
+    }
+
+    pub fn name(&self) -> &'static str {
+        CMD_NAMES.iter().find(|&&(_, c)| c == *self).map(|&(n, _)| n).unwrap()
+    }
+
+    pub fn names() -> Vec<&'static str> {
+        CMD_NAMES.iter().map(|&(n, _)| n).collect()
+    }
+
+    fn is_kill(&self) -> bool {
+        matches!(*self,
+                 EditCmd::KillLine |
+                 EditCmd::BackwardKillLine |
+                 EditCmd::UnixWordRubout |
+                 EditCmd::KillWord |
+                 EditCmd::BackwardKillWord)
+    }
+}
+
+/// What a key is bound to: an editing command, or a tin fn to be run.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Binding {
+    Cmd(EditCmd),
+    Fn(String),
+}
+
+/// Maps the keys read at the prompt (whole escape sequences included) to what they do.
+pub type Keymap = HashMap<String, Binding>;
+
+/// Keymaps: the keymaps of each editing mode, as `bind` has left them.
+pub struct Keymaps {
+    pub emacs: Keymap,
+    pub vi_insert: Keymap,
+    pub vi_command: Keymap,
+}
+
+pub const KEYMAP_NAMES: [&str; 3] = ["emacs", "vi-insert", "vi-command"];
+
+impl Keymaps {
+    pub fn new() -> Self {
+        Keymaps {
+            emacs: emacs_keymap(),
+            vi_insert: vi_insert_keymap(),
+            vi_command: vi_command_keymap(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Keymap> {
+        match name {
+            "emacs" => Some(&self.emacs),
+            "vi-insert" => Some(&self.vi_insert),
+            "vi-command" => Some(&self.vi_command),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Keymap> {
+        match name {
+            "emacs" => Some(&mut self.emacs),
+            "vi-insert" => Some(&mut self.vi_insert),
+            "vi-command" => Some(&mut self.vi_command),
+            _ => None,
+        }
+    }
+}
+
+pub fn emacs_keymap() -> Keymap {
+    let mut km = Keymap::new();
+    for &(k, c) in [("\n", EditCmd::Accept),
+                    ("\r", EditCmd::Accept),
+                    ("\t", EditCmd::Complete),
+                    ("\x02", EditCmd::BackwardChar),
+                    ("\x1b[D", EditCmd::BackwardChar),
+                    ("\x1bOD", EditCmd::BackwardChar),
+                    ("\x06", EditCmd::ForwardChar),
+                    ("\x1b[C", EditCmd::ForwardChar),
+                    ("\x1bOC", EditCmd::ForwardChar),
+                    ("\x1bb", EditCmd::BackwardWord),
+                    ("\x1b[1;5D", EditCmd::BackwardWord),
+                    ("\x1b[1;3D", EditCmd::BackwardWord),
+                    ("\x1bf", EditCmd::ForwardWord),
+                    ("\x1b[1;5C", EditCmd::ForwardWord),
+                    ("\x1b[1;3C", EditCmd::ForwardWord),
+                    ("\x01", EditCmd::BeginningOfLine),
+                    ("\x1b[H", EditCmd::BeginningOfLine),
+                    ("\x1bOH", EditCmd::BeginningOfLine),
+                    ("\x1b[1~", EditCmd::BeginningOfLine),
+                    ("\x05", EditCmd::EndOfLine),
+                    ("\x1b[F", EditCmd::EndOfLine),
+                    ("\x1bOF", EditCmd::EndOfLine),
+                    ("\x1b[4~", EditCmd::EndOfLine),
+                    ("\x1b[3~", EditCmd::DeleteChar),
+                    ("\x04", EditCmd::DeleteCharOrEof),
+                    ("\x7f", EditCmd::BackwardDeleteChar),
+                    ("\x08", EditCmd::BackwardDeleteChar),
+                    ("\x0b", EditCmd::KillLine),
+                    ("\x15", EditCmd::BackwardKillLine),
+                    ("\x17", EditCmd::UnixWordRubout),
+                    ("\x1bd", EditCmd::KillWord),
+                    ("\x1b\x7f", EditCmd::BackwardKillWord),
+                    ("\x19", EditCmd::Yank),
+                    ("\x1by", EditCmd::YankPop),
+                    ("\x14", EditCmd::TransposeChars),
+                    ("\x1f", EditCmd::Undo),
+                    ("\x0c", EditCmd::ClearScreen),
+                    ("\x03", EditCmd::Abandon),
+                    ("\x10", EditCmd::PreviousHistory),
+                    ("\x1b[A", EditCmd::PreviousHistory),
+                    ("\x1bOA", EditCmd::PreviousHistory),
+                    ("\x0e", EditCmd::NextHistory),
+                    ("\x1b[B", EditCmd::NextHistory),
+                    ("\x1bOB", EditCmd::NextHistory),
+                    ("\x12", EditCmd::ReverseSearch),
+                    ("\x13", EditCmd::ForwardSearch)]
+        .iter() {
+        km.insert(k.to_string(), Binding::Cmd(c));
+    }
+    km
+}
+
+/// Vi's insert mode keeps the emacs keys, save that escape goes to normal mode.
+pub fn vi_insert_keymap() -> Keymap {
+    let mut km = emacs_keymap();
+    km.insert("\x1b".to_string(), Binding::Cmd(EditCmd::ViCommandMode));
+    km
+}
+
+/// The keys in vi's normal mode which aren't vi commands of their own.
+pub fn vi_command_keymap() -> Keymap {
+    let mut km = Keymap::new();
+    for &(k, c) in [("\n", EditCmd::Accept),
+                    ("\r", EditCmd::Accept),
+                    ("\x1b[D", EditCmd::BackwardChar),
+                    ("\x1bOD", EditCmd::BackwardChar),
+                    ("\x1b[C", EditCmd::ForwardChar),
+                    ("\x1bOC", EditCmd::ForwardChar),
+                    ("\x1b[H", EditCmd::BeginningOfLine),
+                    ("\x1bOH", EditCmd::BeginningOfLine),
+                    ("\x1b[F", EditCmd::EndOfLine),
+                    ("\x1bOF", EditCmd::EndOfLine),
+                    ("\x1b[3~", EditCmd::DeleteChar),
+                    ("\x04", EditCmd::DeleteCharOrEof),
+                    ("\x7f", EditCmd::BackwardChar),
+                    ("\x0c", EditCmd::ClearScreen),
+                    ("\x03", EditCmd::Abandon),
+                    ("\x1b[A", EditCmd::PreviousHistory),
+                    ("\x1bOA", EditCmd::PreviousHistory),
+                    ("\x1b[B", EditCmd::NextHistory),
+                    ("\x1bOB", EditCmd::NextHistory),
+                    ("/", EditCmd::ReverseSearch),
+                    ("?", EditCmd::ForwardSearch),
+                    ("\x12", EditCmd::ReverseSearch),
+                    ("\x13", EditCmd::ForwardSearch)]
+        .iter() {
+        km.insert(k.to_string(), Binding::Cmd(c));
+    }
+    km
+}
+
+// the code points which take no room of their own: combining marks, joiners
+// and variation selectors
+const ZERO_WIDTH: [(u32, u32); 22] = [(0x0300, 0x036f), (0x0483, 0x0489), (0x0591, 0x05bd),
+                                      (0x0610, 0x061a), (0x064b, 0x065f), (0x0670, 0x0670),
+                                      (0x06d6, 0x06dc), (0x06df, 0x06e4), (0x0900, 0x0903),
+                                      (0x093a, 0x094f), (0x0e31, 0x0e31), (0x0e34, 0x0e3a),
+                                      (0x0e47, 0x0e4e), (0x1ab0, 0x1aff), (0x1dc0, 0x1dff),
+                                      (0x200b, 0x200f), (0x20d0, 0x20ff), (0x302a, 0x302f),
+                                      (0x3099, 0x309a), (0xfe00, 0xfe0f), (0xfe20, 0xfe2f),
+                                      (0xe0100, 0xe01ef)];
+
+// the code points terminals give two columns: East Asian wide and fullwidth
+// ones, and emoji
+const WIDE: [(u32, u32); 32] = [(0x1100, 0x115f), (0x231a, 0x231b), (0x2329, 0x232a),
+                                (0x23e9, 0x23ec), (0x23f0, 0x23f3), (0x25fd, 0x25fe),
+                                (0x2614, 0x2615), (0x2648, 0x2653), (0x26a1, 0x26a1),
+                                (0x26aa, 0x26ab), (0x26bd, 0x26be), (0x26c4, 0x26c5),
+                                (0x2705, 0x2705), (0x270a, 0x270b), (0x2728, 0x2728),
+                                (0x274c, 0x274c), (0x2753, 0x2757), (0x2795, 0x2797),
+                                (0x2b1b, 0x2b1c), (0x2b50, 0x2b55), (0x2e80, 0x303e),
+                                (0x3041, 0x33ff), (0x3400, 0x4dbf), (0x4e00, 0xa4cf),
+                                (0xa960, 0xa97f), (0xac00, 0xd7a3), (0xf900, 0xfaff),
+                                (0xfe10, 0xfe19), (0xfe30, 0xfe6f), (0xff00, 0xff60),
+                                (0xffe0, 0xffe6), (0x1f000, 0x3fffd)];
+
+fn in_table(c: char, table: &[(u32, u32)]) -> bool {
+    let c = c as u32;
+    table.iter().any(|&(lo, hi)| lo <= c && c <= hi)
+}
+
+/// How many columns the terminal gives c.
+pub fn char_width(c: char) -> usize {
+    if (c as u32) < 0x20 || (0x7f <= (c as u32) && (c as u32) < 0xa0) || in_table(c, &ZERO_WIDTH) {
+        0
+    } else if in_table(c, &WIDE) {
+        2
+    } else {
+        1
+    }
+}
+
+/// How many columns the terminal gives s: a grapheme cluster takes up the
+/// room of its first char, or two columns if asked to look like an emoji.
+pub fn width(s: &str) -> usize {
+    s.graphemes(true)
+        .map(|g| {
+            let w = g.chars().next().map(char_width).unwrap_or(0);
+            if g.contains('\u{fe0f}') { 2 } else { w }
+        })
+        .sum()
+}
+
+const KILL_RING_LEN: usize = 16;
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric()
+}
+
+/// Editor: the line being edited, and the editing commands which work on it
+/// alone; the prompt takes care of the rest (history, completion, etc.).
+/// The cursor is a byte index into the line, but it moves by whole grapheme
+/// clusters -- a 'char' here is what the user sees as one.
+pub struct Editor {
+    pub buf: String,
+    pub idx: usize,
+
+    kill_ring: Vec<String>,
+    yanked: Option<(usize, usize)>, // where the last yank went, for yank-pop
+    undo: Vec<(String, usize)>,
+    last: Option<EditCmd>,
+}
+
+impl Editor {
+    pub fn new() -> Self {
+        Editor {
+            buf: String::new(),
+            idx: 0,
+            kill_ring: Vec::new(),
+            yanked: None,
+            undo: Vec::new(),
+            last: None,
+        }
+    }
+
+    /// Starts on a new line; the kill ring is kept.
+    pub fn reset(&mut self) {
+        self.buf.clear();
+        self.idx = 0;
+        self.yanked = None;
+        self.undo.clear();
+        self.last = None;
+    }
+
+    pub fn prev_char(&self, i: usize) -> usize {
+        self.buf[..i].grapheme_indices(true).next_back().map(|(j, _)| j).unwrap_or(0)
+    }
+
+    pub fn next_char(&self, i: usize) -> usize {
+        self.buf[i..].graphemes(true).next().map(|g| i + g.len()).unwrap_or(i)
+    }
+
+    /// Whether i is somewhere the cursor can be, between two chars.
+    pub fn is_boundary(&self, i: usize) -> bool {
+        i == self.buf.len() || self.buf.grapheme_indices(true).any(|(j, _)| j == i)
+    }
+
+    /// How many chars come before i.
+    pub fn char_pos(&self, i: usize) -> usize {
+        self.buf[..i].graphemes(true).count()
+    }
+
+    /// Where the nth char starts.
+    pub fn char_idx(&self, n: usize) -> usize {
+        self.buf.grapheme_indices(true).nth(n).map(|(i, _)| i).unwrap_or(self.buf.len())
+    }
+
+    // where in the line from..to the cursor comes closest to col columns in
+    fn at_column(&self, from: usize, to: usize, col: usize) -> usize {
+        let mut w = 0;
+        for (i, g) in self.buf[from..to].grapheme_indices(true) {
+            w += width(g);
+            if w > col {
+                return from + i;
+            }
+        }
+        to
+    }
+
+    /// Moves up a line of a multi-line buffer, staying in the same column if
+    /// we can.  Returns false if we're on the first line already.
+    pub fn line_up(&mut self) -> bool {
+        let start = match self.buf[..self.idx].rfind('\n') {
+            Some(n) => n + 1,
+            None => return false,
+        };
+        let col = width(&self.buf[start..self.idx]);
+        let prev = self.buf[..start - 1].rfind('\n').map(|n| n + 1).unwrap_or(0);
+        self.idx = self.at_column(prev, start - 1, col);
+        true
+    }
+
+    /// Moves down a line; returns false if we're on the last line already.
+    pub fn line_down(&mut self) -> bool {
+        let next = match self.buf[self.idx..].find('\n') {
+            Some(n) => self.idx + n + 1,
+            None => return false,
+        };
+        let start = self.buf[..self.idx].rfind('\n').map(|n| n + 1).unwrap_or(0);
+        let col = width(&self.buf[start..self.idx]);
+        let end = self.buf[next..].find('\n').map(|n| next + n).unwrap_or(self.buf.len());
+        self.idx = self.at_column(next, end, col);
+        true
+    }
+
+    // skips back over chars which aren't in a word, then ones which are
+    fn word_start(&self, i: usize, in_word: &Fn(char) -> bool) -> usize {
+        let mut i = i;
+        while i > 0 && !in_word(self.buf[self.prev_char(i)..].chars().next().unwrap()) {
+            i = self.prev_char(i);
+        }
+        while i > 0 && in_word(self.buf[self.prev_char(i)..].chars().next().unwrap()) {
+            i = self.prev_char(i);
+        }
+        i
+    }
+
+    fn word_end(&self, i: usize) -> usize {
+        let mut i = i;
+        while i < self.buf.len() && !is_word(self.buf[i..].chars().next().unwrap()) {
+            i = self.next_char(i);
+        }
+        while i < self.buf.len() && is_word(self.buf[i..].chars().next().unwrap()) {
+            i = self.next_char(i);
+        }
+        i
+    }
+
+    // kills from..to onto the kill ring, adding to the last kill if the
+    // command before this one killed too
+    fn kill(&mut self, from: usize, to: usize, cmd: EditCmd) {
+        if from == to {
+            return;
+        }
+        let text = self.buf[from..to].to_string();
+        self.buf.drain(from..to);
+        self.idx = from;
+
+        if self.last.map(|l| l.is_kill()).unwrap_or(false) && !self.kill_ring.is_empty() {
+            let back = matches!(cmd,
+                                EditCmd::BackwardKillLine |
+                                EditCmd::UnixWordRubout |
+                                EditCmd::BackwardKillWord);
+            let last = self.kill_ring.last_mut().unwrap();
+            if back {
+                last.insert_str(0, &text);
+            } else {
+                last.push_str(&text);
+            }
+        } else {
+            self.kill_ring.push(text);
+            if self.kill_ring.len() > KILL_RING_LEN {
+                self.kill_ring.remove(0);
+            }
+        }
+    }
+
+    pub fn char_at(&self, i: usize) -> Option<char> {
+        self.buf.get(i..).and_then(|s| s.chars().next())
+    }
+
+    /// Saves the line as it is, for undo to go back to.
+    pub fn snapshot(&mut self) {
+        self.undo.push((self.buf.clone(), self.idx));
+    }
+
+    /// Carries on the inserts from before rather than starting anew, so
+    /// they're undone together with what came before them.
+    pub fn continue_insert(&mut self) {
+        self.last = Some(EditCmd::SelfInsert);
+    }
+
+    /// Ends a run of inserts, so the next one is undone by itself.
+    pub fn end_insert(&mut self) {
+        self.last = None;
+    }
+
+    /// Copies from..to onto the kill ring.
+    pub fn copy(&mut self, from: usize, to: usize) {
+        if from < to {
+            self.kill_ring.push(self.buf[from..to].to_string());
+            if self.kill_ring.len() > KILL_RING_LEN {
+                self.kill_ring.remove(0);
+            }
+        }
+    }
+
+    /// Moves from..to onto the kill ring.
+    pub fn cut(&mut self, from: usize, to: usize) {
+        self.copy(from, to);
+        self.buf.drain(from..to);
+        self.idx = from;
+    }
+
+    /// Puts count copies of the last kill before the cursor, or after the
+    /// char at it; the cursor ends up on the last char put in.
+    pub fn paste(&mut self, after: bool, count: usize) {
+        if let Some(text) = self.kill_ring.last().cloned() {
+            let at = if after { self.next_char(self.idx) } else { self.idx };
+            let text = text.repeat(count);
+            self.buf.insert_str(at, &text);
+            self.idx = self.prev_char(at + text.len());
+        }
+    }
+
+    fn yank(&mut self) {
+        if let Some(text) = self.kill_ring.last().cloned() {
+            let at = self.idx;
+            self.buf.insert_str(at, &text);
+            self.idx = at + text.len();
+            self.yanked = Some((at, self.idx));
+        }
+    }
+
+    /// Inserts s at the cursor; a run of inserts is undone all at once.
+    pub fn insert(&mut self, s: &str) {
+        if self.last != Some(EditCmd::SelfInsert) {
+            self.undo.push((self.buf.clone(), self.idx));
+        }
+        self.buf.insert_str(self.idx, s);
+        self.idx += s.len();
+        self.last = Some(EditCmd::SelfInsert);
+    }
+
+    /// Runs cmd on the line.  Returns false if cmd isn't one the editor
+    /// knows how to run by itself.
+    pub fn run(&mut self, cmd: EditCmd) -> bool {
+        let before = (self.buf.clone(), self.idx);
+        let len = self.buf.len();
+        let idx = self.idx;
+
+        match cmd {
+            EditCmd::BackwardChar => self.idx = self.prev_char(idx),
+            EditCmd::ForwardChar => self.idx = self.next_char(idx),
+            EditCmd::BackwardWord => self.idx = self.word_start(idx, &is_word),
+            EditCmd::ForwardWord => self.idx = self.word_end(idx),
+            EditCmd::BeginningOfLine => self.idx = 0,
+            EditCmd::EndOfLine => self.idx = len,
+            EditCmd::DeleteChar => {
+                let to = self.next_char(idx);
+                self.buf.drain(idx..to);
+            }
+            EditCmd::BackwardDeleteChar => {
+                let from = self.prev_char(idx);
+                self.buf.drain(from..idx);
+                self.idx = from;
+            }
+            EditCmd::KillLine => self.kill(idx, len, cmd),
+            EditCmd::BackwardKillLine => self.kill(0, idx, cmd),
+            EditCmd::UnixWordRubout => {
+                let from = self.word_start(idx, &|c: char| !c.is_whitespace());
+                self.kill(from, idx, cmd);
+            }
+            EditCmd::KillWord => {
+                let to = self.word_end(idx);
+                self.kill(idx, to, cmd);
+            }
+            EditCmd::BackwardKillWord => {
+                let from = self.word_start(idx, &is_word);
+                self.kill(from, idx, cmd);
+            }
+            EditCmd::Yank => self.yank(),
+            EditCmd::YankPop => {
+                match (self.last, self.yanked) {
+                    (Some(EditCmd::Yank), Some((from, to))) |
+                    (Some(EditCmd::YankPop), Some((from, to))) => {
+                        self.buf.drain(from..to);
+                        self.idx = from;
+                        if let Some(k) = self.kill_ring.pop() {
+                            self.kill_ring.insert(0, k);
+                        }
+                        self.yank();
+                    }
+                    _ => {}
+                }
+            }
+            EditCmd::TransposeChars => {
+                if idx > 0 && self.buf.chars().count() > 1 {
+                    // at the end of the line, the last two chars are swapped
+                    let mid = if idx == len { self.prev_char(idx) } else { idx };
+                    let from = self.prev_char(mid);
+                    let to = self.next_char(mid);
+                    let a = self.buf[from..mid].to_string();
+                    self.buf.insert_str(to, &a);
+                    self.buf.drain(from..mid);
+                    self.idx = to;
+                }
+            }
+            EditCmd::Undo => {
+                if let Some((buf, idx)) = self.undo.pop() {
+                    self.buf = buf;
+                    self.idx = idx;
+                }
+            }
+            _ => {
+                self.last = Some(cmd);
+                return false;
+            }
+        }
+
+        if cmd != EditCmd::Undo && self.buf != before.0 {
+            self.undo.push(before);
+        }
+        self.last = Some(cmd);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ed(buf: &str, idx: usize) -> Editor {
+        let mut ed = Editor::new();
+        ed.buf = buf.to_string();
+        ed.idx = idx;
+        ed
+    }
+
+    #[test]
+    fn widths() {
+        assert_eq!(width("abc"), 3);
+        assert_eq!(width("\u{5b57}"), 2);
+        // a combining accent goes with the char before it
+        assert_eq!(width("e\u{301}"), 1);
+        assert_eq!(width("\u{2764}\u{fe0f}"), 2);
+        assert_eq!(char_width('\x1b'), 0);
+    }
+
+    #[test]
+    fn moves_by_grapheme() {
+        let e = ed("ae\u{301}b", 0);
+        assert_eq!(e.next_char(1), 4);
+        assert_eq!(e.prev_char(4), 1);
+        assert_eq!(e.char_pos(4), 2);
+        assert_eq!(e.char_idx(2), 4);
+        assert!(!e.is_boundary(2));
+    }
+
+    #[test]
+    fn words() {
+        let mut e = ed("foo bar-baz", 0);
+        e.run(EditCmd::ForwardWord);
+        assert_eq!(e.idx, 3);
+        e.run(EditCmd::ForwardWord);
+        assert_eq!(e.idx, 7);
+        e.run(EditCmd::EndOfLine);
+        e.run(EditCmd::BackwardWord);
+        assert_eq!(e.idx, 8);
+        // ^W goes back to whitespace, not just a word boundary
+        e.run(EditCmd::EndOfLine);
+        e.run(EditCmd::UnixWordRubout);
+        assert_eq!(e.buf, "foo ");
+    }
+
+    #[test]
+    fn kills_run_together() {
+        let mut e = ed("one two three", 13);
+        e.run(EditCmd::BackwardKillWord);
+        e.run(EditCmd::BackwardKillWord);
+        assert_eq!(e.buf, "one ");
+        e.run(EditCmd::Yank);
+        assert_eq!(e.buf, "one two three");
+        assert_eq!(e.kill_ring.len(), 1);
+    }
+
+    #[test]
+    fn yank_pop_cycles() {
+        let mut e = ed("a b", 0);
+        e.run(EditCmd::KillWord);
+        e.run(EditCmd::EndOfLine);
+        e.run(EditCmd::BackwardKillWord);
+        assert_eq!(e.buf, " ");
+        e.run(EditCmd::Yank);
+        assert_eq!(e.buf, " b");
+        e.run(EditCmd::YankPop);
+        assert_eq!(e.buf, " a");
+        // yank-pop does nothing unless it follows a yank
+        e.run(EditCmd::BackwardChar);
+        e.run(EditCmd::YankPop);
+        assert_eq!(e.buf, " a");
+    }
+
+    #[test]
+    fn transposes() {
+        let mut e = ed("abc", 1);
+        e.run(EditCmd::TransposeChars);
+        assert_eq!((e.buf.as_str(), e.idx), ("bac", 2));
+        e.run(EditCmd::EndOfLine);
+        e.run(EditCmd::TransposeChars);
+        assert_eq!((e.buf.as_str(), e.idx), ("bca", 3));
+    }
+
+    #[test]
+    fn undoes_inserts_at_once() {
+        let mut e = Editor::new();
+        e.insert("a");
+        e.insert("b");
+        e.run(EditCmd::BackwardChar);
+        e.insert("c");
+        assert_eq!(e.buf, "acb");
+        e.run(EditCmd::Undo);
+        assert_eq!(e.buf, "ab");
+        e.run(EditCmd::Undo);
+        assert_eq!(e.buf, "");
+    }
+
+    #[test]
+    fn lines_keep_column() {
+        let mut e = ed("abcdef\nxy\n\u{5b57}\u{5b57}z", 4);
+        assert!(e.line_down());
+        assert_eq!(e.idx, 9);
+        assert!(e.line_down());
+        // column 2 is the start of the second wide char
+        assert_eq!(e.idx, 13);
+        assert!(!e.line_down());
+        assert!(e.line_up());
+        assert!(e.line_up());
+        assert_eq!(e.idx, 2);
+        assert!(!e.line_up());
+    }
+}
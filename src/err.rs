@@ -1,7 +1,17 @@
 use std::io;
 use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
 use opts;
 
+// set whenever a warning or worse is given, so the shell can tell that the
+// statement it's running has hit an error
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a warning has been given since this was last called.
+pub fn take_warned() -> bool {
+    WARNED.swap(false, Ordering::SeqCst)
+}
+
 pub fn debug_print(msg: &str, level: u8) {
     if level >= opts::get("__tin_debug").unwrap().parse::<u8>().unwrap() {
         let _ = writeln!(&mut io::stderr(),
@@ -15,6 +25,10 @@ pub fn debug_print(msg: &str, level: u8) {
                          msg);
     }
 
+    if level >= 2 {
+        WARNED.store(true, Ordering::SeqCst);
+    }
+
     if level == 3 {
         panic!("Unrecoverable error encountered");
     }
@@ -35,3 +49,11 @@ macro_rules! warn {
 macro_rules! err {
     ( $($arg:tt)* ) => ($crate::err::debug_print(&format!($($arg)*), 3))
 }
+
+// like try!, for Options: gives back None from the fn if $e is None
+macro_rules! try_opt {
+    ($e:expr) => (match $e {
+        Some(x) => x,
+        None => return None,
+    })
+}
@@ -1,5 +1,7 @@
 use std::process::exit;
+use std::io;
 use std::io::ErrorKind;
+use std::io::Write;
 use std::path::PathBuf;
 use std::ffi::CString;
 use std::ffi::OsStr;
@@ -7,6 +9,8 @@ use std::os::unix::ffi::OsStrExt;
 
 use shell::Shell;
 
+use builtins;
+
 use posix;
 use opts;
 
@@ -52,7 +56,7 @@ impl BinProcess {
 }
 
 impl Process for BinProcess {
-    fn exec(self, _sh: &mut Shell, pgid: Option<Pgid>) -> Option<Child> {
+    fn exec(self, sh: &mut Shell, pgid: Option<Pgid>) -> Option<Child> {
         match posix::fork(opts::is_set("__tin_inter"), pgid) {
             Err(e) => {
                 // oops. gotta bail.
@@ -67,8 +71,15 @@ impl Process for BinProcess {
 
                 let e = posix::execv(pb2c(self.to_exec).as_ptr(), self.argv.as_ptr());
                 if e.kind() == ErrorKind::NotFound {
-                    // TODO: custom handler function
-                    warn!("Command '{}' not found.", self.m_args[0].to_str().unwrap());
+                    // it went away since we looked, so let the handler have it
+                    let args = self.m_args
+                        .iter()
+                        .map(|a| Arg::Str(a.to_string_lossy().into_owned()))
+                        .collect();
+                    let h = builtins::not_found_handler(sh);
+                    let r = (*h.run)(args, sh, None);
+                    let _ = io::stdout().flush();
+                    exit(r);
                 } else {
                     warn!("Could not exec: {}", e);
                 }
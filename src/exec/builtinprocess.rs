@@ -1,9 +1,7 @@
-use std::fs::File;
-use std::os::unix::io::FromRawFd;
-use std::io::BufReader;
+use std::io;
+use std::io::Write;
 
 use std::process::exit;
-use std::mem;
 
 use opts;
 use posix;
@@ -26,7 +24,7 @@ use exec::Arg;
 // NOTE: we assume there are no Rd args if !to_exec.rd_cap, since they *should*
 // have been taken care of with push_arg.  Thus we assume there is no need to
 // adapt Rd args.
-fn adapt_args(to_exec: &Builtin, av: Vec<Arg>) -> Vec<Arg> {
+pub fn adapt_args(to_exec: &Builtin, av: Vec<Arg>) -> Vec<Arg> {
     if to_exec.bl_cap && to_exec.pat_cap {
         return av;
     }
@@ -51,6 +49,19 @@ fn adapt_args(to_exec: &Builtin, av: Vec<Arg>) -> Vec<Arg> {
     ret
 }
 
+// Redirects trailing the last block of a compound command (e.g. `ifx x { ... } -> f`)
+// apply to the whole command, rather than being passed along as args.
+fn hoist_rds(av: &mut Vec<Arg>, inner: &mut ProcessInner) {
+    let last_bl = match av.iter().rposition(|a| a.is_bl()) {
+        Some(i) => i,
+        None => return,
+    };
+    if av[last_bl + 1..].iter().all(|a| a.is_rd()) {
+        for a in av.drain(last_bl + 1..) {
+            inner.rds.push(a.unwrap_rd());
+        }
+    }
+}
 
 /// Struct representing a builtin to be executed -- essentially, anything which does
 /// not require a call to execv to run.
@@ -69,6 +80,10 @@ impl BuiltinProcess {
         }
     }
 
+    pub fn has_block(&self) -> bool {
+        self.argv.iter().any(|a| a.is_bl())
+    }
+
     pub fn from_fn(f: sym::Fn) -> Self {
         BuiltinProcess {
             to_exec: builtins::fn_builtin(f),
@@ -79,11 +94,15 @@ impl BuiltinProcess {
 }
 
 impl Process for BuiltinProcess {
-    fn exec(self, sh: &mut Shell, pgid: Option<Pgid>) -> Option<Child> {
-        // TODO: fork happens iff
-        //  - proc is bg
-        //  - stdout is some
-        //  - there are output redirects
+    fn exec(mut self, sh: &mut Shell, pgid: Option<Pgid>) -> Option<Child> {
+        if self.to_exec.rd_cap && self.to_exec.bl_cap {
+            hoist_rds(&mut self.argv, &mut self.inner);
+        }
+
+        // We only need to fork when our stdout is piped, since the next process
+        // in the pipeline must be able to run alongside us.  Otherwise we
+        // redirect the shell itself and put things back afterwards.
+        // TODO: also fork when proc is bg
         if self.inner.ch_stdout.is_some() {
             match posix::fork(opts::is_set("__tin_inter"), pgid) {
                 Err(e) => {
@@ -92,6 +111,7 @@ impl Process for BuiltinProcess {
                     None
                 }
                 Ok(None) => {
+                    sh.drop_traps();
                     let a = self.argv;
                     let i = self.inner;
                     let te = self.to_exec;
@@ -107,32 +127,25 @@ impl Process for BuiltinProcess {
                 Ok(Some(ch_pid)) => Some(Child::new(ch_pid)),
             }
         } else {
-            let mut i = self.inner;
+            let i = self.inner;
             let a = self.argv;
             let te = self.to_exec;
 
-            unsafe {
-                let br = if i.ch_stdin.is_some() {
-                    let rd_stdin = mem::replace(&mut i.ch_stdin, None);
-                    Some(BufReader::new(File::from_raw_fd(rd_stdin.unwrap()
-                        .into_raw())))
-                } else {
-                    None
-                };
-
-                let ret_rd = match i.redirect(true) {
-                    Ok(x) => Some(x),
-                    Err(e) => {
-                        warn!("Could not redirect: {}", e);
-                        None
-                    }
-                };
-                let argv = adapt_args(&te, a);
-                sh.status_code = (*te.run)(argv, sh, br);
-                if let Some(rd) = ret_rd {
-                    let _ = rd.redirect(false);
+            let _ = io::stdout().flush();
+            // the builtin isn't run at all if its redirections can't be made
+            let rd = match i.redirect(true) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Could not redirect: {}", e);
+                    sh.status_code = 1;
+                    return None;
                 }
-            }
+            };
+            let argv = adapt_args(&te, a);
+            sh.status_code = (*te.run)(argv, sh, None);
+            // anything still buffered belongs to the redirected stdout
+            let _ = io::stdout().flush();
+            let _ = rd.redirect(false);
 
             None
         }
@@ -113,6 +113,14 @@ impl Job {
         r
     }
 
+    /// Whether any of the job's commands is given a block to run.
+    pub fn runs_block(&self) -> bool {
+        self.procs.iter().any(|p| match **p {
+            ProcStruct::BuiltinProc(ref b) => b.has_block(),
+            ProcStruct::BinProc(_) => false,
+        })
+    }
+
     pub fn new(_cmd: String) -> Self {
         Job {
             spawned: false,
@@ -35,6 +35,7 @@ pub enum Redir {
     RdFileOut(i32, String, bool), // file substitutions
     RdFileIn(i32, String), //  - e.g. -2> errs.txt
     RdStringIn(i32, String), // here-string/here-documents
+    RdClose(i32), // fd closure, e.g. -5>-
 }
 
 #[derive(Clone)]
@@ -134,6 +135,7 @@ impl Arg {
                     }
                     Redir::RdFileIn(a, src) => format!("{}<- {}", a, src),
                     Redir::RdStringIn(a, src) => format!("{}<<- {}", a, src),
+                    Redir::RdClose(a) => format!("-{}>-", a),
                 }
             }
         }
@@ -186,6 +188,7 @@ impl Arg {
                         ret.push(format!("{}<<-", a));
                         ret.push(src);
                     }
+                    Redir::RdClose(a) => ret.push(format!("-{}>-", a)),
                 }
             }
         }
@@ -271,21 +274,38 @@ impl ProcessInner {
     }
 
     // returns a ProcessInner which will perform reverse redirections (if rev == true),
-    // essentially undoing this call
-    // FIXME: I am certain this/the reversal process leaves files dangling
-    fn redirect(mut self, rev: bool) -> Result<ProcessInner> {
+    // essentially undoing this call.  if a redirection fails, those done before it
+    // are undone before the error is returned
+    fn redirect(self, rev: bool) -> Result<ProcessInner> {
         let mut res = ProcessInner::new();
+        match self.redirect_saving(rev, &mut res) {
+            Ok(()) => Ok(res),
+            Err(e) => {
+                if rev {
+                    let _ = res.redirect(false);
+                }
+                Err(e)
+            }
+        }
+    }
 
-        // we don't want to un-redirect pipes, so don't add
-        // a reverse to res
+    // does the redirections, recording in res how to undo them if rev
+    fn redirect_saving(mut self, rev: bool, res: &mut ProcessInner) -> Result<()> {
+        // a piped stdin is un-redirected like anything else, so that blocks run in
+        // the shell process can read from it; we always fork for a piped stdout.
         if let Some(read) = self.ch_stdin {
+            if rev {
+                try!(res.save_fd(0));
+            }
             try!(posix::set_stdin(read));
         }
         if let Some(write) = self.ch_stdout {
             try!(posix::set_stdout(write));
         }
 
-        while let Some(rd) = self.rds.pop() {
+        // redirections are performed left-to-right, so that e.g. `-> log -2>1`
+        // sends both stdout and stderr to log
+        for rd in self.rds.drain(..) {
             match rd {
                 Redir::RdArgOut(dest) => {
                     // unimplemented - does not need reversed
@@ -299,21 +319,21 @@ impl ProcessInner {
                     if src == -2 {
                         // '&'
                         if rev {
-                            res.rds.push(Redir::RdFdOut(1, try!(posix::dup_fd(1))));
-                            res.rds.push(Redir::RdFdOut(2, try!(posix::dup_fd(2))));
+                            try!(res.save_fd(1));
+                            try!(res.save_fd(2));
                         }
                         try!(posix::dup_fds(1, dest));
                         try!(posix::dup_fds(2, dest));
                     } else {
                         if rev {
-                            res.rds.push(Redir::RdFdOut(src, try!(posix::dup_fd(src))));
+                            try!(res.save_fd(src));
                         }
                         try!(posix::dup_fds(src, dest));
                     }
                 }
                 Redir::RdFdIn(src, dest) => {
                     if rev {
-                        res.rds.push(Redir::RdFdOut(src, try!(posix::dup_fd(src))));
+                        try!(res.save_fd(src));
                     }
                     try!(posix::dup_fds(src, dest));
                 }
@@ -321,42 +341,79 @@ impl ProcessInner {
                     let fi = try!(OpenOptions::new()
                         .write(true)
                         .create(true)
+                        .truncate(!app)
                         .append(app)
                         .open(dest));
                     let fd = fi.as_raw_fd();
                     if src == -2 {
                         // '&'
                         if rev {
-                            res.rds.push(Redir::RdFdOut(1, try!(posix::dup_fd(1))));
-                            res.rds.push(Redir::RdFdOut(2, try!(posix::dup_fd(2))));
+                            try!(res.save_fd(1));
+                            try!(res.save_fd(2));
                         }
 
                         try!(posix::dup_fds(1, fd));
                         try!(posix::dup_fds(2, fd));
+                        if fd == 1 || fd == 2 {
+                            mem::forget(fi);
+                        }
                     } else {
                         if rev {
-                            res.rds.push(Redir::RdFdOut(src, try!(posix::dup_fd(src))));
+                            try!(res.save_fd(src));
                         }
 
                         try!(posix::dup_fds(src, fd));
+                        // the file may have been opened onto the very fd we wanted
+                        if fd == src {
+                            mem::forget(fi);
+                        }
                     }
-                    mem::forget(fi);
                 }
                 Redir::RdFileIn(dest, src) => {
                     if rev {
-                        res.rds.push(Redir::RdFdOut(dest, try!(posix::dup_fd(dest))));
+                        try!(res.save_fd(dest));
                     }
                     let fi = try!(OpenOptions::new().read(true).open(src));
                     try!(posix::dup_fds(dest, fi.as_raw_fd()));
-                    mem::forget(fi);
+                    if fi.as_raw_fd() == dest {
+                        mem::forget(fi);
+                    }
                 }
                 Redir::RdStringIn(dest, src_str) => {
                     // unimplemented
                     println!(" string '{}' => fd '{}'", src_str, dest);
                 }
+                Redir::RdClose(fd) => {
+                    if rev {
+                        try!(res.save_fd(fd));
+                    }
+                    try!(posix::close_fd(fd));
+                }
             }
         }
 
-        Ok(res)
+        Ok(())
+    }
+
+    // records the redirections which will restore fd to its current state.
+    // these are put at the front of rds so that restoring happens in reverse order.
+    fn save_fd(&mut self, fd: i32) -> Result<()> {
+        if posix::is_open_fd(fd) {
+            let saved = try!(posix::dup_fd_cloexec(fd));
+            self.rds.insert(0, Redir::RdClose(saved));
+            self.rds.insert(0, Redir::RdFdOut(fd, saved));
+        } else {
+            self.rds.insert(0, Redir::RdClose(fd));
+        }
+        Ok(())
     }
 }
+
+/// Performs the given redirections on the shell process itself.  If rev is true,
+/// returns the redirections which undo them, to be passed back in later.
+pub fn redirect_shell(rds: Vec<Redir>, rev: bool) -> Result<Vec<Redir>> {
+    let mut pi = ProcessInner::new();
+    pi.rds = rds;
+    let res = try!(pi.redirect(rev));
+    Ok(res.rds)
+}
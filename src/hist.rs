@@ -1,6 +1,194 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use opts;
+use posix;
+
+/// How hist_find matches entries against what it's looking for.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Match {
+    Prefix,
+    Substring,
+    Fuzzy, // the query's chars, in order
+}
+
+/// A line of history, along with when it was run and how that went.
+#[derive(Clone)]
+pub struct HistEntry {
+    pub line: String,
+    pub time: u64,
+    pub status: Option<i32>,
+}
+
+impl HistEntry {
+    fn new(line: &str) -> Self {
+        HistEntry {
+            line: line.to_string(),
+            time: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            status: None,
+        }
+    }
+
+    // entries are stored one to a line as ': time:status;line', with any
+    // newlines in the line escaped
+    fn to_file_line(&self) -> String {
+        let mut res = format!(": {}:{};",
+                              self.time,
+                              self.status.map(|s| s.to_string()).unwrap_or_default());
+        for c in self.line.chars() {
+            match c {
+                '\\' => res.push_str("\\\\"),
+                '\n' => res.push_str("\\n"),
+                c => res.push(c),
+            }
+        }
+        res.push('\n');
+        res
+    }
+
+    fn from_file_line(fline: &str) -> Option<Self> {
+        if fline.trim().is_empty() {
+            return None;
+        }
+
+        // lines without the prefix (e.g., added by hand) are taken as they are
+        let (time, status, esc) = match parse_prefix(fline) {
+            Some(x) => x,
+            None => return Some(HistEntry {
+                line: fline.to_string(),
+                time: 0,
+                status: None,
+            }),
+        };
+
+        let mut line = String::new();
+        let mut chars = esc.chars();
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                match chars.next() {
+                    Some('n') => line.push('\n'),
+                    Some(c) => line.push(c),
+                    None => line.push('\\'),
+                }
+            } else {
+                line.push(c);
+            }
+        }
+
+        Some(HistEntry {
+            line,
+            time,
+            status,
+        })
+    }
+}
+
+fn parse_prefix(fline: &str) -> Option<(u64, Option<i32>, &str)> {
+    if !fline.starts_with(": ") {
+        return None;
+    }
+    let semi = try_opt!(fline.find(';'));
+    let mut fields = fline[2..semi].splitn(2, ':');
+    let time = try_opt!(fields.next().and_then(|t| t.parse::<u64>().ok()));
+    let status = match fields.next() {
+        Some("") => None,
+        Some(s) => {
+            match s.parse::<i32>() {
+                Ok(s) => Some(s),
+                Err(_) => return None,
+            }
+        }
+        None => return None,
+    };
+    Some((time, status, &fline[semi + 1..]))
+}
+
+// reads the entries in a history file, oldest first.  an entry is written
+// when it's added, and again with its status once it has run, which takes
+// the place of the first
+fn parse_file(contents: &str) -> Vec<HistEntry> {
+    let mut res: Vec<HistEntry> = Vec::new();
+    for e in contents.lines().filter_map(HistEntry::from_file_line) {
+        if e.status.is_some() {
+            if let Some(i) = res.iter().rposition(|x| x.time == e.time && x.status.is_none()) {
+                res[i] = e;
+                continue;
+            }
+        }
+        res.push(e);
+    }
+    res
+}
+
+// how many entries to keep; 0 means there's no limit
+fn hist_size() -> usize {
+    opts::get("__tin_histsize").and_then(|s| s.parse::<usize>().ok()).unwrap_or(1000)
+}
+
+// which duplicates to keep: 'consecutive', 'erase' or 'all'
+fn hist_dups() -> String {
+    opts::get("__tin_histdups").unwrap_or_default()
+}
+
+// applies the duplicate policy and size limit to entries, which are oldest first
+fn hist_trim(entries: Vec<HistEntry>, dups: &str, size: usize) -> Vec<HistEntry> {
+    let mut res: Vec<HistEntry> = Vec::new();
+    for e in entries {
+        match dups {
+            "all" => {}
+            "erase" => res.retain(|x| x.line != e.line),
+            _ => {
+                if res.last().map(|x| x.line == e.line).unwrap_or(false) {
+                    continue;
+                }
+            }
+        }
+        res.push(e);
+    }
+
+    if size > 0 && res.len() > size {
+        let extra = res.len() - size;
+        res.drain(..extra);
+    }
+    res
+}
+
+// finds query's chars in line in order, returning where the first one is
+fn fuzzy_find(line: &str, query: &str) -> Option<usize> {
+    let mut qchars = query.chars().peekable();
+    let mut start = None;
+    for (i, c) in line.char_indices() {
+        match qchars.peek() {
+            Some(&q) if q == c => {
+                if start.is_none() {
+                    start = Some(i);
+                }
+                qchars.next();
+            }
+            Some(_) => {}
+            None => break,
+        }
+    }
+    if qchars.peek().is_none() {
+        Some(start.unwrap_or(0))
+    } else {
+        None
+    }
+}
+
 pub struct Histvec {
-    hist: Vec<String>,
+    hist: Vec<HistEntry>,
     hpos: usize,
+    file: Option<PathBuf>,
+    unfinished: bool,
 }
 
 impl Histvec {
@@ -8,13 +196,115 @@ impl Histvec {
         Histvec {
             hist: Vec::new(),
             hpos: 0,
+            file: None,
+            unfinished: false,
+        }
+    }
+
+    /// Reads in the history file at path, trimming it down if it has grown
+    /// too long, and appends new entries to it from then on.
+    pub fn load(&mut self, path: PathBuf) {
+        match Histvec::read_file(&path) {
+            Ok(entries) => {
+                self.hist = entries;
+                self.hist.reverse();
+                self.hpos = 0;
+                self.file = Some(path);
+            }
+            Err(e) => warn!("history: Could not load '{}': {}", path.display(), e),
+        }
+    }
+
+    // the file to read and write, unless we're told otherwise
+    fn file_or(&self, path: Option<PathBuf>) -> io::Result<PathBuf> {
+        match path.or(self.file.clone()).or(opts::get("__tin_histfile").map(PathBuf::from)) {
+            Some(p) => Ok(p),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no history file")),
+        }
+    }
+
+    /// Replaces the contents of the history file (or path) with the history.
+    pub fn hist_write(&self, path: Option<PathBuf>) -> io::Result<()> {
+        let path = try!(self.file_or(path));
+        // it's only emptied once we hold the lock
+        let mut f = try!(OpenOptions::new().write(true).create(true).truncate(false).open(&path));
+        try!(posix::lock_file(&f, true));
+        let out = self.hist.iter().rev().map(|e| e.to_file_line()).collect::<String>();
+        try!(f.set_len(0));
+        f.write_all(out.as_bytes())
+    }
+
+    /// Adds the entries in the history file (or path) to the history, as if
+    /// they had just been run -- e.g., to pick up what other shells have run.
+    pub fn hist_read(&mut self, path: Option<PathBuf>) -> io::Result<()> {
+        let path = try!(self.file_or(path));
+        let mut f = try!(File::open(&path));
+        try!(posix::lock_file(&f, false));
+        let mut contents = String::new();
+        try!(f.read_to_string(&mut contents));
+
+        // the entry still running stays the newest
+        let cur = if self.unfinished {
+            Some(self.hist.remove(0))
+        } else {
+            None
+        };
+        let mut entries = self.hist.drain(..).rev().collect::<Vec<_>>();
+        let mut read = parse_file(&contents);
+        // which is already in the file, if it's ours
+        if let Some(ref cur) = cur {
+            if let Some(i) = read.iter().rposition(|e| e.time == cur.time && e.line == cur.line) {
+                read.remove(i);
+            }
+        }
+        entries.extend(read);
+        self.hist = hist_trim(entries, &hist_dups(), hist_size());
+        self.hist.reverse();
+        if let Some(cur) = cur {
+            self.hist.insert(0, cur);
+        }
+        self.hpos = 0;
+        Ok(())
+    }
+
+    fn read_file(path: &PathBuf) -> io::Result<Vec<HistEntry>> {
+        let mut f = try!(OpenOptions::new()
+                             .read(true)
+                             .write(true)
+                             .create(true)
+                             .truncate(false)
+                             .open(path));
+        // other shells may be appending to the file as we go
+        try!(posix::lock_file(&f, true));
+
+        let mut contents = String::new();
+        try!(f.read_to_string(&mut contents));
+        let len = contents.lines().filter(|l| !l.trim().is_empty()).count();
+        let entries = hist_trim(parse_file(&contents), &hist_dups(), hist_size());
+
+        // rewritten if it has grown too long, or holds entries twice
+        if entries.len() != len {
+            let out = entries.iter().map(|e| e.to_file_line()).collect::<String>();
+            try!(f.seek(SeekFrom::Start(0)));
+            try!(f.set_len(0));
+            try!(f.write_all(out.as_bytes()));
+        }
+        Ok(entries)
+    }
+
+    fn append_file(&self, entry: &HistEntry) -> io::Result<()> {
+        if let Some(ref path) = self.file {
+            let mut f = try!(OpenOptions::new().append(true).create(true).open(path));
+            try!(posix::lock_file(&f, true));
+            try!(f.write_all(entry.to_file_line().as_bytes()));
         }
+        Ok(())
     }
 
     pub fn hist_up(&mut self) -> Option<&str> {
         if self.hpos < self.hist.len() {
             self.hpos = self.hpos + 1;
-            Some(&self.hist[self.hpos - 1])
+            Some(&self.hist[self.hpos - 1].line)
         } else {
             None
         }
@@ -24,7 +314,7 @@ impl Histvec {
         if self.hpos > 0 {
             self.hpos = self.hpos - 1;
             if self.hpos > 0 {
-                Some(&self.hist[self.hpos - 1])
+                Some(&self.hist[self.hpos - 1].line)
             } else {
                 Some("")
             }
@@ -33,16 +323,308 @@ impl Histvec {
         }
     }
 
+    /// Returns the entry at idx, 0 being the newest.
+    pub fn hist_get(&self, idx: usize) -> Option<&str> {
+        self.hist.get(idx).map(|e| &e.line as &str)
+    }
+
+    pub fn hist_entry(&self, idx: usize) -> Option<&HistEntry> {
+        self.hist.get(idx)
+    }
+
+    pub fn hist_len(&self) -> usize {
+        self.hist.len()
+    }
+
+    /// Looks up an entry the way '!' history expansion does: '!' is the
+    /// last entry, 'n' the nth (counting from 1, the oldest), '-n' the nth
+    /// last, and anything else the last which starts with it.  The entry
+    /// still being run, if there is one, is passed over.
+    pub fn hist_lookup(&self, spec: &str) -> Option<String> {
+        let skip = if self.unfinished { 1 } else { 0 };
+        let idx = if spec == "!" {
+            Some(skip)
+        } else if let Ok(n) = spec.parse::<usize>() {
+            self.hist.len().checked_sub(n)
+        } else if spec.starts_with('-') && spec[1..].parse::<usize>().is_ok() {
+            spec[1..].parse::<usize>().ok().and_then(|n| (skip + n).checked_sub(1))
+        } else {
+            self.hist_find(spec, skip, true, Match::Prefix).map(|(i, _)| i)
+        };
+        idx.and_then(|i| self.hist_get(i)).map(|l| l.to_string())
+    }
+
+    /// Removes the entry at idx, 0 being the newest.
+    pub fn hist_delete(&mut self, idx: usize) -> bool {
+        if idx >= self.hist.len() {
+            return false;
+        }
+        if idx == 0 {
+            self.unfinished = false;
+        }
+        self.hist.remove(idx);
+        self.hpos = 0;
+        true
+    }
+
+    pub fn hist_clear(&mut self) {
+        self.hist.clear();
+        self.hpos = 0;
+        self.unfinished = false;
+    }
+
+    /// Changes what the entry still being run says was run -- e.g., to what
+    /// it ran in turn.
+    pub fn hist_amend(&mut self, line: &str) {
+        if self.unfinished {
+            self.hist[0].line = line.to_string();
+        }
+    }
+
+    /// Where hist_up and hist_down are in the history: 0 is the line being
+    /// edited, and n is the entry at n - 1.
+    pub fn hist_pos(&self) -> usize {
+        self.hpos
+    }
+
+    /// Moves hist_up and hist_down to go on from the entry at idx.
+    pub fn hist_goto(&mut self, idx: usize) {
+        if idx < self.hist.len() {
+            self.hpos = idx + 1;
+        }
+    }
+
+    /// Moves hist_up and hist_down back to the line being edited.
+    pub fn hist_reset(&mut self) {
+        self.hpos = 0;
+    }
+
+    /// Finds the first entry from idx on, going to older entries if older and
+    /// newer ones otherwise, which matches query.  Returns its index and where
+    /// in it the match starts.
+    pub fn hist_find(&self,
+                     query: &str,
+                     from: usize,
+                     older: bool,
+                     m: Match)
+                     -> Option<(usize, usize)> {
+        if self.hist.is_empty() {
+            return None;
+        }
+        let idxs: Box<Iterator<Item = usize>> = if older {
+            Box::new(from..self.hist.len())
+        } else if from < self.hist.len() {
+            Box::new((0..from + 1).rev())
+        } else {
+            Box::new((0..self.hist.len()).rev())
+        };
+
+        for i in idxs {
+            let line = &self.hist[i].line;
+            let found = match m {
+                Match::Prefix => if line.starts_with(query) { Some(0) } else { None },
+                Match::Substring => line.find(query),
+                Match::Fuzzy => fuzzy_find(line, query),
+            };
+            if let Some(off) = found {
+                return Some((i, off));
+            }
+        }
+        None
+    }
+
+    /// Adds a new entry, and writes it to the history file straight away, so
+    /// it's there even if what it runs never comes back.
     pub fn hist_add(&mut self, nentry: &str) {
-        if self.hist.len() == 0 || nentry != self.hist[0] {
-            self.hist.insert(0, nentry.to_string());
+        self.push(nentry, &hist_dups(), hist_size());
+        if self.unfinished {
+            if let Err(e) = self.append_file(&self.hist[0]) {
+                warn!("history: Could not save: {}", e);
+            }
         }
+    }
+
+    /// Like hist_add, but with the duplicate policy and size limit given,
+    /// and nothing written to the history file.
+    pub fn push(&mut self, nentry: &str, dups: &str, size: usize) {
         self.hpos = 0;
+        self.unfinished = false;
+        match dups {
+            "all" => {}
+            "erase" => self.hist.retain(|x| x.line != nentry),
+            _ => {
+                if !self.hist.is_empty() && nentry == self.hist[0].line {
+                    return;
+                }
+            }
+        }
+
+        self.hist.insert(0, HistEntry::new(nentry));
+        self.unfinished = true;
+
+        if size > 0 {
+            self.hist.truncate(size);
+        }
+    }
+
+    /// Records the status of the entry last added, in the history file too.
+    pub fn hist_finish(&mut self, status: i32) {
+        if !self.unfinished {
+            return;
+        }
+        self.unfinished = false;
+        self.hist[0].status = Some(status);
+        if let Err(e) = self.append_file(&self.hist[0]) {
+            warn!("history: Could not save: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a history of lines, oldest first, each of which has been run
+    fn hv(lines: &[&str]) -> Histvec {
+        let mut ht = Histvec::new();
+        for l in lines {
+            ht.push(l, "all", 0);
+            ht.hist_finish(0);
+        }
+        ht
+    }
+
+    fn lines(ht: &Histvec) -> Vec<&str> {
+        (0..ht.hist_len()).filter_map(|i| ht.hist_get(i)).collect()
+    }
+
+    fn trimmed(entries: &[&str], dups: &str, size: usize) -> Vec<String> {
+        let entries = entries.iter().map(|l| HistEntry::new(l)).collect();
+        hist_trim(entries, dups, size).into_iter().map(|e| e.line).collect()
+    }
+
+    #[test]
+    fn looks_up() {
+        let ht = hv(&["ls", "cd /tmp", "echo hi", "ls -l"]);
+        assert_eq!(ht.hist_lookup("!"), Some("ls -l".to_string()));
+        assert_eq!(ht.hist_lookup("1"), Some("ls".to_string()));
+        assert_eq!(ht.hist_lookup("4"), Some("ls -l".to_string()));
+        assert_eq!(ht.hist_lookup("5"), None);
+        assert_eq!(ht.hist_lookup("0"), None);
+        assert_eq!(ht.hist_lookup("-1"), Some("ls -l".to_string()));
+        assert_eq!(ht.hist_lookup("-3"), Some("cd /tmp".to_string()));
+        assert_eq!(ht.hist_lookup("-5"), None);
+        assert_eq!(ht.hist_lookup("cd"), Some("cd /tmp".to_string()));
+        assert_eq!(ht.hist_lookup("ls"), Some("ls -l".to_string()));
+        assert_eq!(ht.hist_lookup("x"), None);
+    }
+
+    #[test]
+    fn looks_up_past_the_running_entry() {
+        let mut ht = hv(&["ls", "echo hi"]);
+        ht.push("history -e ls", "all", 0);
+        assert_eq!(ht.hist_lookup("!"), Some("echo hi".to_string()));
+        assert_eq!(ht.hist_lookup("-1"), Some("echo hi".to_string()));
+        assert_eq!(ht.hist_lookup("h"), None);
+        // what's counted from the oldest is as listed, the running entry too
+        assert_eq!(ht.hist_lookup("3"), Some("history -e ls".to_string()));
+
+        // history -e keeps what was run, not how it was asked for
+        ht.hist_amend("ls");
+        assert_eq!(lines(&ht), ["ls", "echo hi", "ls"]);
+        ht.hist_finish(0);
+        ht.hist_amend("nope");
+        assert_eq!(ht.hist_get(0), Some("ls"));
+    }
+
+    #[test]
+    fn finds() {
+        // newest first: cargo build, git push, git commit, make test
+        let ht = hv(&["make test", "git commit", "git push", "cargo build"]);
+        assert_eq!(ht.hist_find("git", 0, true, Match::Prefix), Some((1, 0)));
+        assert_eq!(ht.hist_find("git", 2, true, Match::Prefix), Some((2, 0)));
+        assert_eq!(ht.hist_find("git", 3, true, Match::Prefix), None);
+        assert_eq!(ht.hist_find("git", 3, false, Match::Prefix), Some((2, 0)));
+        assert_eq!(ht.hist_find("make", 2, false, Match::Prefix), None);
+        assert_eq!(ht.hist_find("make", 10, false, Match::Prefix), Some((3, 0)));
+        assert_eq!(ht.hist_find("push", 0, true, Match::Prefix), None);
+        assert_eq!(ht.hist_find("push", 0, true, Match::Substring), Some((1, 4)));
+        assert_eq!(ht.hist_find("it", 2, true, Match::Substring), Some((2, 1)));
+        assert_eq!(ht.hist_find("gcm", 0, true, Match::Fuzzy), Some((2, 0)));
+        assert_eq!(ht.hist_find("bld", 0, true, Match::Fuzzy), Some((0, 6)));
+        assert_eq!(ht.hist_find("tb", 0, true, Match::Fuzzy), None);
+        assert_eq!(Histvec::new().hist_find("", 0, true, Match::Prefix), None);
     }
 
-    pub fn hist_print(&self) {
-        for elt in self.hist.iter().rev() {
-            println!("  {}", elt);
+    #[test]
+    fn drops_dups() {
+        let mut ht = Histvec::new();
+        for l in &["a", "a", "b", "a"] {
+            ht.push(l, "consecutive", 0);
+        }
+        assert_eq!(lines(&ht), ["a", "b", "a"]);
+
+        let mut ht = Histvec::new();
+        for l in &["a", "b", "a"] {
+            ht.push(l, "erase", 0);
+        }
+        assert_eq!(lines(&ht), ["a", "b"]);
+
+        let mut ht = Histvec::new();
+        for l in &["a", "b", "c"] {
+            ht.push(l, "all", 2);
         }
+        assert_eq!(lines(&ht), ["c", "b"]);
+    }
+
+    #[test]
+    fn trims() {
+        let entries = ["a", "a", "b", "a", "c"];
+        assert_eq!(trimmed(&entries, "consecutive", 0), ["a", "b", "a", "c"]);
+        assert_eq!(trimmed(&entries, "erase", 0), ["b", "a", "c"]);
+        assert_eq!(trimmed(&entries, "all", 0), entries);
+        // the oldest go first
+        assert_eq!(trimmed(&entries, "all", 2), ["a", "c"]);
+        assert_eq!(trimmed(&entries, "erase", 2), ["a", "c"]);
+    }
+
+    #[test]
+    fn parses_prefixes() {
+        assert_eq!(parse_prefix(": 123:0;ls"), Some((123, Some(0), "ls")));
+        assert_eq!(parse_prefix(": 123:;ls"), Some((123, None, "ls")));
+        assert_eq!(parse_prefix(": 123:-1;a; b"), Some((123, Some(-1), "a; b")));
+        assert_eq!(parse_prefix(": 123;ls"), None);
+        assert_eq!(parse_prefix(": 12x:0;ls"), None);
+        assert_eq!(parse_prefix(": 123:x;ls"), None);
+        assert_eq!(parse_prefix("ls"), None);
+    }
+
+    #[test]
+    fn round_trips_entries() {
+        let e = HistEntry {
+            line: "for x in a\\nb {\n    echo (x)\n}".to_string(),
+            time: 1500000000,
+            status: Some(1),
+        };
+        let fline = e.to_file_line();
+        assert_eq!(fline, ": 1500000000:1;for x in a\\\\nb {\\n    echo (x)\\n}\n");
+        assert_eq!(fline.lines().count(), 1);
+        let back = HistEntry::from_file_line(fline.trim_end_matches('\n')).unwrap();
+        assert_eq!(back.line, e.line);
+        assert_eq!(back.time, e.time);
+        assert_eq!(back.status, e.status);
+
+        // a line written by hand is taken as it is
+        let e = HistEntry::from_file_line("echo a\\nb").unwrap();
+        assert_eq!((&e.line as &str, e.time, e.status), ("echo a\\nb", 0, None));
+        assert!(HistEntry::from_file_line("  ").is_none());
+    }
+
+    #[test]
+    fn reads_statuses_after_entries() {
+        let contents = ": 10:;exec sh\n: 10:;a\n: 10:;b\n: 10:1;b\n: 11:;c\n: 10:0;a\n";
+        let entries = parse_file(contents);
+        let got = entries.iter().map(|e| (&e.line as &str, e.status)).collect::<Vec<_>>();
+        assert_eq!(got, [("exec sh", None), ("a", Some(0)), ("b", Some(1)), ("c", None)]);
     }
 }
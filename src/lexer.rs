@@ -14,7 +14,7 @@ pub enum TokenType {
 
 // TODO: lazy_static! these
 fn build_word(tok: String) -> TokenType {
-    let r_syntax = Regex::new(r"^(~>|-(\d+|&)?>(\d+|\+)?)$").unwrap();
+    let r_syntax = Regex::new(r"^(~>|-(\d+|&)?>(\d+|\+|-)?)$").unwrap();
     let l_syntax = Regex::new(r"^((\d+)?<(<|\d+)?-|<~)$").unwrap();
 
     if r_syntax.is_match(&tok) || l_syntax.is_match(&tok) {
@@ -85,6 +85,31 @@ impl Lexer {
     }
 }
 
+/// Whether text, taken a line at a time as the shell would, leaves nothing
+/// (a block, parens, quotes) open at the end.
+pub fn is_complete(text: &str) -> bool {
+    let mut lx_st = None;
+    for line in text.split('\n') {
+        let line = line.trim().to_string();
+        let lexer = match lx_st.take() {
+            Some(lx) => Lexer::with_state(line, lx),
+            None => Lexer::new(line),
+        };
+        for token_res in lexer {
+            match token_res {
+                Ok(_) => {}
+                Err(TokenException::Incomplete(lx, _)) => {
+                    lx_st = Some(lx);
+                    break;
+                }
+                // it's complete enough for the parser to complain about it
+                Err(_) => break,
+            }
+        }
+    }
+    lx_st.is_none()
+}
+
 impl Iterator for Lexer {
     type Item = Result<TokenType, TokenException>;
 
@@ -7,12 +7,17 @@ mod parser;
 mod lexer;
 mod shell;
 mod hist;
+mod editor;
+mod vi;
+mod term;
 mod compl;
 mod exec;
 mod posix;
 mod opts;
 
+use std::collections::HashMap;
 use std::env;
+use std::path;
 
 use prompt::LineState;
 
@@ -28,6 +33,18 @@ fn setup(file: bool, exec: Option<String>) -> Shell {
         ls: LineState::Normal,
         st: sym::Symtable::new(),
         ht: hist::Histvec::new(),
+        km: editor::Keymaps::new(),
+
+        srcs: Vec::new(),
+        ret_val: None,
+
+        calls: Vec::new(),
+        file: None,
+        lineno: 1,
+
+        traps: HashMap::new(),
+        in_trap: false,
+        capturing: 0,
     };
 
     // interactive init (read rc file, posix::init)
@@ -48,8 +65,10 @@ fn setup(file: bool, exec: Option<String>) -> Shell {
 
     // if file/exec, do that
     if file {
-        let p: prompt::FilePrompt = prompt::FilePrompt::new(&exec.unwrap()).unwrap();
+        let path = exec.unwrap();
+        let p: prompt::FilePrompt = prompt::FilePrompt::new(&path).unwrap();
         sh.pr = Box::new(p);
+        sh.file = Some(path::PathBuf::from(path));
         sh.input_loop(None, true);
     } else if let Some(cmd) = exec {
         sh.input_loop(Some(vec![cmd]), false);
@@ -57,6 +76,9 @@ fn setup(file: bool, exec: Option<String>) -> Shell {
 
     if opts::is_set("__tin_inter") {
         sh.pr = Box::new(prompt::StdPrompt::new());
+        if let Some(hf) = opts::get("__tin_histfile") {
+            sh.ht.load(path::PathBuf::from(hf));
+        }
     }
 
     sh
@@ -167,4 +189,6 @@ fn main() {
             println!("exit");
         }
     }
+
+    sh.exit_trap();
 }
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::env;
 use std::sync::{Arc, Once, ONCE_INIT};
 use std::error::Error;
 use std::fmt;
@@ -103,6 +104,55 @@ pub fn init(inter: bool, login: bool, noexec: bool, tinrc: String) {
 
     // do not enable by default
     t.insert("__tin_psplit", rw_opt!());
+    t.insert("__tin_histexpand", rw_opt!());
+
+    // what Up and Down match with what's been typed: entries it 'prefix'es,
+    // ones with it as a 'substring', ones with its chars in order ('fuzzy'),
+    // or 'none', to step through everything.  ^R and ^S look for a substring
+    // unless this is 'fuzzy'
+    t.insert("__tin_histmatch",
+             Opt {
+                 val: Some("prefix".to_string()),
+                 wr_cond: Arc::new(|x| matches!(x.map(|x| x as &str),
+                                           Some("prefix" | "substring" | "fuzzy" | "none"))),
+             });
+
+    // which keys edit the line at the prompt: 'emacs' or 'vi' ones
+    t.insert("__tin_editmode",
+             Opt {
+                 val: Some("emacs".to_string()),
+                 wr_cond: Arc::new(|x| matches!(x.map(|x| x as &str),
+                                           Some("emacs" | "vi"))),
+             });
+
+    // how deeply fns may call each other, kept well short of where the stack
+    // would overflow; 0 means there's no limit
+    t.insert("__tin_max_depth",
+             Opt {
+                 val: Some("200".to_string()),
+                 wr_cond: Arc::new(|x| x.map(|x| x.parse::<usize>().is_ok()).unwrap_or(false)),
+             });
+
+    // where interactive history is kept, as of when the tinrc has been run;
+    // unset, history is forgotten on exit
+    t.insert("__tin_histfile",
+             rw_opt!(env::var("HOME").ok().map(|h| format!("{}/.tin_history", h))));
+
+    // how many history entries to keep; 0 means there's no limit
+    t.insert("__tin_histsize",
+             Opt {
+                 val: Some("1000".to_string()),
+                 wr_cond: Arc::new(|x| x.map(|x| x.parse::<usize>().is_ok()).unwrap_or(false)),
+             });
+
+    // which duplicate history entries to drop: 'consecutive' ones, every
+    // older copy ('erase'), or none of them ('all')
+    t.insert("__tin_histdups",
+             Opt {
+                 val: Some("consecutive".to_string()),
+                 wr_cond: Arc::new(|x| matches!(x.map(|x| x as &str),
+                                           Some("consecutive" | "erase" | "all"))),
+             });
 }
 
 pub fn is_opt(key: &str) -> bool {
@@ -12,6 +12,7 @@ use std::mem;
 use prompt::LineState;
 
 use shell::Shell;
+use hist::Histvec;
 
 use exec::job::Job;
 use exec::Process;
@@ -23,11 +24,93 @@ use exec::ProcStruct::BuiltinProc;
 use exec::Redir;
 use exec::Arg;
 
+use builtins;
+
 use lexer::TokenType;
 use lexer::TokenException;
 use lexer::Lexer;
 use lexer::LexerState;
 
+// builtins which () may run in the shell itself: they leave its state as it
+// was, bar 'fn' making the handle it gives back, which has to be the shell's
+const CAPTURE_BUILTINS: [&str; 6] = ["fn", "type", "which", "_prompt",
+                                             "_prompt_comment", "_prompt_continue"];
+
+// whether () can run pstmt in the shell itself: it must start with a fn, so
+// that what the fn sets sticks, or with a builtin which changes nothing; any
+// other builtin (cd, say) runs in a subshell, as ever
+fn runs_in_shell(sh: &mut Shell, pstmt: &str) -> bool {
+    let cmd = match Lexer::new(pstmt.to_string()).next() {
+        Some(Ok(TokenType::Word(w))) => w,
+        _ => return false,
+    };
+
+    match sh.st.resolve_exec(&cmd) {
+        Some(sym::SymE::Fn(_)) => true,
+        Some(sym::SymE::Builtin(b)) => CAPTURE_BUILTINS.contains(&b.name),
+        _ => false,
+    }
+}
+
+/// Expands '!!', '!n', '!-n' and '!prefix' in an interactive line to the
+/// entries they name from the history (see Histvec::hist_lookup), outside
+/// of single quotes.  Fails if one can't be found.
+pub fn hist_expand(ht: &Histvec, line: &str) -> Result<String, String> {
+    let mut res = String::new();
+    let mut quot = false;
+    let mut bs = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        // only a bare '!' starts an expansion
+        let literal = match c {
+            '!' => bs || quot,
+            _ => true,
+        };
+        if literal {
+            if !bs {
+                match c {
+                    '\'' => quot = !quot,
+                    '\\' => bs = true,
+                    _ => {}
+                }
+            } else {
+                bs = false;
+            }
+            res.push(c);
+            continue;
+        }
+
+        let mut spec = String::new();
+        if chars.peek() == Some(&'!') {
+            spec.push(chars.next().unwrap());
+        } else {
+            if chars.peek() == Some(&'-') {
+                spec.push(chars.next().unwrap());
+            }
+            while let Some(&n) = chars.peek() {
+                if n.is_alphanumeric() || "_-./".contains(n) {
+                    spec.push(n);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+        }
+
+        // e.g., the '!' of a splat
+        if spec.is_empty() || spec == "-" {
+            res.push('!');
+            res.push_str(&spec);
+            continue;
+        }
+        match ht.hist_lookup(&spec) {
+            Some(l) => res.push_str(&l),
+            None => return Err(format!("'!{}' is not in the history", spec)),
+        }
+    }
+    Ok(res)
+}
+
 fn p_resolve(sh: &mut Shell, mut pstmt: String, ps: &ParseState) -> Vec<String> {
     if pstmt == "?" {
         return vec![sh.status_code.to_string()];
@@ -44,6 +127,7 @@ fn p_resolve(sh: &mut Shell, mut pstmt: String, ps: &ParseState) -> Vec<String>
     let res = match sh.st.resolve_varish(&pstmt) {
         Some(sym::SymV::Var(s)) |
         Some(sym::SymV::Environment(s)) => s,
+        None if runs_in_shell(sh, &pstmt) => sh.input_loop_capture(vec![pstmt]),
         None => sh.input_loop_collect(Some(vec![pstmt])),
     };
 
@@ -325,7 +409,7 @@ fn redir_parse(tok: String) -> (Option<Redir>, Option<RedirBuf>) {
         "<~" => (None, Some(RedirBuf::RdArgIn)),
         "<<-" => (None, Some(RedirBuf::RdStringIn(0))),
         _ => {
-            let rd_out = Regex::new(r"^-(&|\d*)>(\+|\d*)$").unwrap();
+            let rd_out = Regex::new(r"^-(&|\d*)>(\+|-|\d*)$").unwrap();
             let rd_in = Regex::new(r"^(\d*)<(\d*)-$").unwrap();
 
             if let Some(caps) = rd_out.captures(&tok) {
@@ -337,6 +421,14 @@ fn redir_parse(tok: String) -> (Option<Redir>, Option<RedirBuf>) {
                 match caps.at(2).unwrap() {
                     "" => (None, Some(RedirBuf::RdFileOut(src_fd, false))),
                     "+" => (None, Some(RedirBuf::RdFileOut(src_fd, true))),
+                    "-" => {
+                        if src_fd == -2 {
+                            warn!("Syntax error: cannot close '&'");
+                            (None, None)
+                        } else {
+                            (Some(Redir::RdClose(src_fd)), None)
+                        }
+                    }
                     e => {
                         let dest_fd = e.parse::<i32>().unwrap();
                         (Some(Redir::RdFdOut(src_fd, dest_fd)), None)
@@ -442,13 +534,11 @@ impl Parser {
                             self.cproc = Box::new(match sh.st.resolve_exec(&tok) {
                                 Some(sym::SymE::Builtin(b)) => BuiltinProc(BuiltinProcess::new(b)),
                                 Some(sym::SymE::Binary(b)) => BinProc(BinProcess::new(&tok, b)),
-                                Some(sym::SymE::Fn(f)) => BuiltinProc(BuiltinProcess::from_fn(f)),
+                                Some(sym::SymE::Fn(f)) => BuiltinProc(BuiltinProcess::from_fn(*f)),
                                 None => {
-                                    // TODO: 'command not found' here, yo
-                                    if !sh.st.subsh {
-                                        warn!("Command '{}' not found.", tok);
-                                    }
-                                    return (None, LineState::Normal);
+                                    let mut p = BuiltinProcess::new(builtins::not_found_handler(sh));
+                                    p.push_arg(Arg::Str(tok));
+                                    BuiltinProc(p)
                                 }
                             });
                         } else {
@@ -493,15 +583,8 @@ impl Parser {
                         warn!("Syntax error: unfinished redirect.");
                         self.rd_buf = None;
                     }
-                    let tokv = tok.split('\n')
-                        .filter_map(|l| {
-                            if l.trim().is_empty() {
-                                None
-                            } else {
-                                Some(l.trim().to_string())
-                            }
-                        })
-                        .collect();
+                    // blank lines are kept so we know which line is which
+                    let tokv = tok.split('\n').map(|l| l.trim().to_string()).collect();
                     self.cproc.push_arg(Arg::Bl(tokv));
                 }
 
@@ -516,14 +599,10 @@ impl Parser {
                 }
                 Err(TokenException::Incomplete(lx_st, buf)) => {
                     self.lx_cache = Some(lx_st);
-                    if self.wd_cache.is_some() {
-                        let mut c = mem::replace(&mut self.wd_cache, None).unwrap();
-                        c.push_str(&buf);
-                        c.push_str("\n");
-                        self.wd_cache = Some(c);
-                    } else {
-                        self.wd_cache = Some(buf);
-                    }
+                    let mut c = self.wd_cache.take().unwrap_or_default();
+                    c.push_str(&buf);
+                    c.push_str("\n");
+                    self.wd_cache = Some(c);
                     return (None, LineState::Continue);
                 }
             }
@@ -539,3 +618,45 @@ impl Parser {
         (Some(new_job), LineState::Normal)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(line: &str) -> Result<String, String> {
+        let mut ht = Histvec::new();
+        for l in &["ls -l", "echo 'a!b'", "cd /tmp", "git status"] {
+            ht.push(l, "all", 0);
+            ht.hist_finish(0);
+        }
+        hist_expand(&ht, line)
+    }
+
+    #[test]
+    fn expands_history() {
+        assert_eq!(expand("!!"), Ok("git status".to_string()));
+        assert_eq!(expand("sudo !!"), Ok("sudo git status".to_string()));
+        assert_eq!(expand("!1"), Ok("ls -l".to_string()));
+        assert_eq!(expand("!-2"), Ok("cd /tmp".to_string()));
+        assert_eq!(expand("!cd && !ls"), Ok("cd /tmp && ls -l".to_string()));
+        assert_eq!(expand("!ec"), Ok("echo 'a!b'".to_string()));
+        assert_eq!(expand("!!; !!"), Ok("git status; git status".to_string()));
+    }
+
+    #[test]
+    fn leaves_quoted_and_bare_bangs() {
+        assert_eq!(expand("echo '!!'"), Ok("echo '!!'".to_string()));
+        assert_eq!(expand("echo \"!!\""), Ok("echo \"git status\"".to_string()));
+        assert_eq!(expand("echo \\!!"), Ok("echo \\!!".to_string()));
+        assert_eq!(expand("echo \\\\!!"), Ok("echo \\\\git status".to_string()));
+        assert_eq!(expand("echo (args)!"), Ok("echo (args)!".to_string()));
+        assert_eq!(expand("echo a! b !-"), Ok("echo a! b !-".to_string()));
+    }
+
+    #[test]
+    fn fails_on_missing_entries() {
+        assert_eq!(expand("!nope"), Err("'!nope' is not in the history".to_string()));
+        assert_eq!(expand("!5"), Err("'!5' is not in the history".to_string()));
+        assert_eq!(expand("echo !-9"), Err("'!-9' is not in the history".to_string()));
+    }
+}
@@ -6,6 +6,8 @@ extern crate libc;
 use std::process::exit;
 
 use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use std::ops::Drop;
 
@@ -296,8 +298,13 @@ pub fn wait_pid(child: &Pid) -> Result<Option<Status>> {
 /// Set stdin to be the current pipe.
 pub fn set_stdin(pipe: ReadPipe) -> Result<()> {
     unsafe {
-        let fd = pipe.into_raw();
-        etry!(libc::dup2(fd, libc::STDIN_FILENO));
+        // the pipe's own fd is closed once it's been dup'ed
+        let fd = (pipe.0).0;
+        if fd != libc::STDIN_FILENO {
+            etry!(libc::dup2(fd, libc::STDIN_FILENO));
+        } else {
+            pipe.into_raw();
+        }
     };
     Ok(())
 }
@@ -305,17 +312,85 @@ pub fn set_stdin(pipe: ReadPipe) -> Result<()> {
 /// Set stdout to be the current pipe.
 pub fn set_stdout(pipe: WritePipe) -> Result<()> {
     unsafe {
-        let fd = pipe.into_raw();
-        etry!(libc::dup2(fd, libc::STDOUT_FILENO));
+        // the pipe's own fd is closed once it's been dup'ed
+        let fd = (pipe.0).0;
+        if fd != libc::STDOUT_FILENO {
+            etry!(libc::dup2(fd, libc::STDOUT_FILENO));
+        } else {
+            pipe.into_raw();
+        }
     };
     Ok(())
 }
 
+/// Reads directly from fd, bypassing any buffering.
+pub fn read_fd(fd: i32, buf: &mut [u8]) -> Result<usize> {
+    let ret = etry!(unsafe {
+        libc::read(fd,
+                   buf.as_mut_ptr() as *mut libc::c_void,
+                   buf.len() as libc::size_t)
+    });
+    Ok(ret as usize)
+}
+
 pub fn dup_fd(src: i32) -> Result<i32> {
     let res = unsafe { etry!(libc::dup(src)) };
     Ok(res)
 }
 
+/// Duplicates src onto a new fd which will be closed upon exec.
+pub fn dup_fd_cloexec(src: i32) -> Result<i32> {
+    let res = unsafe { etry!(libc::fcntl(src, libc::F_DUPFD_CLOEXEC, 0)) };
+    Ok(res)
+}
+
+pub fn close_fd(fd: i32) -> Result<()> {
+    unsafe {
+        etry!(libc::close(fd));
+    }
+    Ok(())
+}
+
+/// Returns true iff fd refers to an open file descriptor.
+pub fn is_open_fd(fd: i32) -> bool {
+    unsafe { libc::fcntl(fd, libc::F_GETFD) >= 0 }
+}
+
+/// Lists the file descriptors currently open in this process.
+pub fn open_fds() -> Vec<i32> {
+    let max = match unsafe { libc::sysconf(libc::_SC_OPEN_MAX) } {
+        n if n > 0 && n < 65536 => n as i32,
+        _ => 1024,
+    };
+    (0..max).filter(|&fd| is_open_fd(fd)).collect()
+}
+
+/// Locks f, shared or exclusively, until it's closed; blocks until we can.
+pub fn lock_file(f: &File, exclusive: bool) -> Result<()> {
+    let op = if exclusive { libc::LOCK_EX } else { libc::LOCK_SH };
+    loop {
+        if unsafe { libc::flock(f.as_raw_fd(), op) } == 0 {
+            return Ok(());
+        }
+        let e = Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// Waits up to timeout milliseconds (forever, if negative) for fd to become readable.
+/// Returns false if we timed out.
+pub fn poll_read(fd: i32, timeout: i32) -> Result<bool> {
+    let mut pfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let res = unsafe { etry!(libc::poll(&mut pfd, 1, timeout)) };
+    Ok(res > 0)
+}
+
 pub fn dup_stdin() -> Result<ReadPipe> {
     let n_fd = try!(dup_fd(0));
     Ok(ReadPipe(FileDesc(n_fd)))
@@ -333,28 +408,161 @@ pub fn dup_fds(src: i32, dest: i32) -> Result<()> {
     Ok(())
 }
 
-pub fn set_signal_ignore(ignore: bool) -> Result<()> {
-    macro_rules! stry {
-        ($e:expr) => {{
-            let res = $e;
-            if res == libc::SIG_ERR {
-                return Err(Error::last_os_error());
-            }
-            res
-        }}
+// the signals an interactive shell ignores
+const INTER_SIGNALS: [i32; 5] = [libc::SIGINT, libc::SIGQUIT, libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU];
+
+const SIGNALS: [(&str, i32); 29] = [("HUP", libc::SIGHUP),
+                                            ("INT", libc::SIGINT),
+                                            ("QUIT", libc::SIGQUIT),
+                                            ("ILL", libc::SIGILL),
+                                            ("TRAP", libc::SIGTRAP),
+                                            ("ABRT", libc::SIGABRT),
+                                            ("BUS", libc::SIGBUS),
+                                            ("FPE", libc::SIGFPE),
+                                            ("KILL", libc::SIGKILL),
+                                            ("USR1", libc::SIGUSR1),
+                                            ("SEGV", libc::SIGSEGV),
+                                            ("USR2", libc::SIGUSR2),
+                                            ("PIPE", libc::SIGPIPE),
+                                            ("ALRM", libc::SIGALRM),
+                                            ("TERM", libc::SIGTERM),
+                                            ("CHLD", libc::SIGCHLD),
+                                            ("CONT", libc::SIGCONT),
+                                            ("STOP", libc::SIGSTOP),
+                                            ("TSTP", libc::SIGTSTP),
+                                            ("TTIN", libc::SIGTTIN),
+                                            ("TTOU", libc::SIGTTOU),
+                                            ("URG", libc::SIGURG),
+                                            ("XCPU", libc::SIGXCPU),
+                                            ("XFSZ", libc::SIGXFSZ),
+                                            ("VTALRM", libc::SIGVTALRM),
+                                            ("PROF", libc::SIGPROF),
+                                            ("WINCH", libc::SIGWINCH),
+                                            ("IO", libc::SIGIO),
+                                            ("SYS", libc::SIGSYS)];
+
+/// Looks up a signal by name ('INT' or 'SIGINT') or number.
+pub fn signal_num(name: &str) -> Option<i32> {
+    if let Ok(n) = name.parse::<i32>() {
+        return SIGNALS.iter().find(|&&(_, sig)| sig == n).map(|&(_, sig)| sig);
     }
 
-    let action = if ignore {
-        libc::SIG_IGN
-    } else {
-        libc::SIG_DFL
-    };
+    let name = name.to_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    SIGNALS.iter().find(|&&(n, _)| n == name).map(|&(_, sig)| sig)
+}
+
+pub fn signal_name(sig: i32) -> Option<&'static str> {
+    SIGNALS.iter().find(|&&(_, s)| s == sig).map(|&(n, _)| n)
+}
+
+// signals which have been caught but not yet dealt with, one bit each
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+// signals which we are set to catch
+static CAUGHT: AtomicU64 = AtomicU64::new(0);
+
+// set when the terminal has been resized, for the prompt to notice
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+// all we can safely do in a signal handler is make a note of it
+extern "C" fn note_signal(sig: libc::c_int) {
+    PENDING.fetch_or(1 << sig, Ordering::SeqCst);
+    if sig == libc::SIGWINCH {
+        RESIZED.store(true, Ordering::SeqCst);
+    }
+}
+
+extern "C" fn note_resize(_sig: libc::c_int) {
+    RESIZED.store(true, Ordering::SeqCst);
+}
+
+/// Notes whenever the terminal is resized; see resized().  A trap on WINCH
+/// takes over from this, but still has the resize noted.
+pub fn catch_resize() -> Result<()> {
+    if CAUGHT.load(Ordering::SeqCst) & (1 << libc::SIGWINCH) != 0 {
+        return Ok(());
+    }
+    unsafe {
+        let mut sa: libc::sigaction = mem::zeroed();
+        sa.sa_sigaction = note_resize as extern "C" fn(libc::c_int) as usize;
+        sa.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut sa.sa_mask);
+        etry!(libc::sigaction(libc::SIGWINCH, &sa, ptr::null_mut()));
+    }
+    Ok(())
+}
+
+/// Whether the terminal has been resized since the last call.
+pub fn resized() -> bool {
+    RESIZED.swap(false, Ordering::SeqCst)
+}
+
+/// The size of the terminal at fd, as (rows, columns).
+pub fn term_size(fd: i32) -> Result<(usize, usize)> {
+    unsafe {
+        let mut ws: libc::winsize = mem::zeroed();
+        etry!(libc::ioctl(fd, libc::TIOCGWINSZ, &mut ws));
+        Ok((ws.ws_row as usize, ws.ws_col as usize))
+    }
+}
+
+/// Takes the signals caught since the last call.
+pub fn pending_signals() -> Vec<i32> {
+    let p = PENDING.swap(0, Ordering::SeqCst);
+    (1..64).filter(|sig| p & (1 << sig) != 0).collect()
+}
+
+pub enum SigAction {
+    Default,
+    Ignore,
+    Catch, // to be picked up later by pending_signals()
+}
+
+pub fn set_signal_action(sig: i32, act: SigAction) -> Result<()> {
     unsafe {
-        stry!(libc::signal(libc::SIGINT, action));
-        stry!(libc::signal(libc::SIGQUIT, action));
-        stry!(libc::signal(libc::SIGTSTP, action));
-        stry!(libc::signal(libc::SIGTTIN, action));
-        stry!(libc::signal(libc::SIGTTOU, action));
+        let mut sa: libc::sigaction = mem::zeroed();
+        sa.sa_sigaction = match act {
+            SigAction::Default => libc::SIG_DFL,
+            SigAction::Ignore => libc::SIG_IGN,
+            SigAction::Catch => note_signal as extern "C" fn(libc::c_int) as usize,
+        };
+        sa.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut sa.sa_mask);
+        etry!(libc::sigaction(sig, &sa, ptr::null_mut()));
+    }
+
+    match act {
+        SigAction::Catch => CAUGHT.fetch_or(1 << sig, Ordering::SeqCst),
+        _ => CAUGHT.fetch_and(!(1 << sig), Ordering::SeqCst),
+    };
+    Ok(())
+}
+
+/// Puts a signal back how the shell had it before anything was done with it.
+pub fn reset_signal(sig: i32, inter: bool) -> Result<()> {
+    if inter && INTER_SIGNALS.contains(&sig) {
+        set_signal_action(sig, SigAction::Ignore)
+    } else if inter && sig == libc::SIGWINCH {
+        try!(set_signal_action(sig, SigAction::Default));
+        catch_resize()
+    } else {
+        set_signal_action(sig, SigAction::Default)
+    }
+}
+
+/// Ignores (or stops ignoring) the signals an interactive shell ignores.
+/// Signals which have been set to be caught are left be when ignoring.
+pub fn set_signal_ignore(ignore: bool) -> Result<()> {
+    let caught = CAUGHT.load(Ordering::SeqCst);
+    for &sig in &INTER_SIGNALS {
+        if ignore && caught & (1 << sig) != 0 {
+            continue;
+        } else if ignore {
+            try!(set_signal_action(sig, SigAction::Ignore));
+        } else {
+            try!(set_signal_action(sig, SigAction::Default));
+        }
     }
     Ok(())
 }
@@ -362,8 +570,11 @@ pub fn set_signal_ignore(ignore: bool) -> Result<()> {
 pub fn fork(inter: bool, pgid: Option<Pgid>) -> Result<Option<Pid>> {
     let c_pid = unsafe { etry!(libc::fork()) };
 
+    // both sides put the child into its group, so that it's there before it
+    // execs, whichever of them gets to run first
     if c_pid == 0 {
         if inter {
+            let _ = put_into_pgrp(None, pgid);
             if let Err(e) = set_signal_ignore(false) {
                 warn!("Child could not listen to signals: {}", e);
                 exit(2);
@@ -373,8 +584,13 @@ pub fn fork(inter: bool, pgid: Option<Pgid>) -> Result<Option<Pid>> {
     } else {
         let pid = Some(Pid(c_pid));
         if inter {
-            try!(put_into_pgrp(pid, pgid));
-            try!(set_signal_ignore(false));
+            match put_into_pgrp(pid, pgid) {
+                // the child's already done it, and exec'd
+                Err(ref e) if e.raw_os_error() == Some(libc::EACCES) => {}
+                r => {
+                    try!(r);
+                }
+            }
         }
 
         Ok(pid)
@@ -435,3 +651,19 @@ pub fn execv(cmd: *const i8, argv: *const *const i8) -> Error {
 
     Error::last_os_error()
 }
+
+/// Formats secs since the epoch as a local 'YYYY-MM-DD HH:MM:SS'.
+pub fn local_time(secs: u64) -> String {
+    let t = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    if unsafe { libc::localtime_r(&t, &mut tm) }.is_null() {
+        return "?".to_string();
+    }
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min,
+            tm.tm_sec)
+}
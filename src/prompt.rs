@@ -3,17 +3,31 @@
 use std::io::prelude::*;
 use std::io;
 use std::io::Result;
+use std::cmp;
+use std::mem;
 use std::str;
 use std::fs::File;
 use std::os::unix::io::AsRawFd;
 
 use compl::complete;
+use editor::Binding;
+use editor::Keymap;
+use editor::EditCmd;
+use editor::Editor;
 
 extern crate termios;
 use self::termios::*;
 
+use hist::Match;
+use lexer;
 use shell::Shell;
 use sym;
+use sym::ScopeSpec;
+use term::Term;
+use vi::Vi;
+use vi::ViAction;
+use opts;
+use posix;
 
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum LineState {
@@ -26,6 +40,11 @@ pub enum LineState {
 // Err(e) = obvious
 pub trait Prompt {
     fn prompt(&mut self, sh: &mut Shell) -> Option<Result<String>>;
+
+    /// Whether the lines come from someone typing them in.
+    fn interactive(&self) -> bool {
+        false
+    }
 }
 
 // This may have been obviated by input_loop
@@ -104,6 +123,89 @@ impl Prompt for FilePrompt {
 }
 
 
+/// TermMode: temporarily changes how the terminal at an fd takes input -- e.g.,
+/// without echo for passwords.  The old settings are restored when dropped.
+pub struct TermMode {
+    fd: i32,
+    saved: Termios,
+}
+
+impl TermMode {
+    pub fn new(fd: i32, echo: bool, canon: bool) -> Result<Self> {
+        let saved = try!(Termios::from_fd(fd));
+        let mut t = saved;
+        if !echo {
+            t.c_lflag &= !(ECHO | ECHONL);
+        }
+        if !canon {
+            t.c_lflag &= !ICANON;
+            t.c_cc[VMIN] = 1;
+            t.c_cc[VTIME] = 0;
+        }
+        try!(tcsetattr(fd, TCSANOW, &t));
+
+        Ok(TermMode {
+            fd,
+            saved,
+        })
+    }
+}
+
+impl Drop for TermMode {
+    fn drop(&mut self) {
+        let _ = tcsetattr(self.fd, TCSANOW, &self.saved);
+    }
+}
+
+// how long the escape sequence keys starts with is: Some(0) if it doesn't
+// start with one, and None if it isn't finished yet.  these are CSI (ESC [,
+// parameters and a final byte), SS3 (ESC O and a byte) and Meta-keys (ESC and
+// any other char)
+fn escape_len(keys: &str) -> Option<usize> {
+    let mut cs = keys.char_indices();
+    if cs.next().map(|(_, c)| c) != Some('\x1b') {
+        return Some(0);
+    }
+    match cs.next() {
+        None => None,
+        Some((_, '[')) => {
+            cs.find(|&(_, c)| ('@'..='~').contains(&c)).map(|(i, c)| i + c.len_utf8())
+        }
+        Some((_, 'O')) => cs.next().map(|(i, c)| i + c.len_utf8()),
+        Some((i, c)) => Some(i + c.len_utf8()),
+    }
+}
+
+// how entries are matched with what's been typed, as __tin_histmatch has it;
+// None means they aren't
+fn hist_match() -> Option<Match> {
+    match opts::get("__tin_histmatch").as_ref().map(|x| x as &str) {
+        Some("prefix") => Some(Match::Prefix),
+        Some("substring") => Some(Match::Substring),
+        Some("fuzzy") => Some(Match::Fuzzy),
+        _ => None,
+    }
+}
+
+// what the prompt does once a key has been handled
+#[derive(PartialEq)]
+enum KeyRes {
+    More,
+    Line,
+    Eof,
+}
+
+// an incremental history search in progress
+struct HistSearch {
+    query: String,
+    older: bool,
+    start: usize,
+    at: Option<(usize, usize)>, // the entry matched, and where in it
+    failed: bool,
+    saved: String,
+    saved_idx: usize,
+}
+
 /// StdPrompt: the standard interactive prompt of the Tin shell.
 /// Supports all manner of interactive goodness -- coloration, tab completion, etc.
 pub struct StdPrompt {
@@ -111,14 +213,27 @@ pub struct StdPrompt {
     termios: Termios,
     in_tcflag: tcflag_t,
     out_tcflag: tcflag_t,
+    in_iflag: tcflag_t,
+    out_iflag: tcflag_t,
 
     print_multi: bool,
     ls: LineState,
 
-    ansi: String,
-    buf: String,
-    prompt_l: usize,
-    idx: usize,
+    keys: String, // what's been typed of a key sequence
+    unread: Option<u8>,
+    ed: Editor,
+    term: Term,
+    prompt_l: usize, // the column the line starts at, after the prompt
+    prompt_rows: usize, // the rows the prompt takes up, less its last
+    queued: Vec<String>, // the lines after the first of a multi-line buffer
+
+    vi_mode: bool,
+    vi: Vi,
+
+    search: Option<HistSearch>,
+    last_query: String,
+    // what Up/Down match, the line before them, and the line they left there
+    nav: Option<(String, String, String)>,
 }
 
 impl StdPrompt {
@@ -131,12 +246,22 @@ impl StdPrompt {
                     term_fi: fi,
                     in_tcflag: 0,
                     out_tcflag: 0,
+                    in_iflag: 0,
+                    out_iflag: 0,
                     ls: LineState::Normal,
                     print_multi: false,
-                    ansi: String::new(),
-                    buf: String::new(),
+                    keys: String::new(),
+                    unread: None,
+                    ed: Editor::new(),
+                    term: Term::new(),
                     prompt_l: 0,
-                    idx: 0,
+                    prompt_rows: 0,
+                    queued: Vec::new(),
+                    vi_mode: false,
+                    vi: Vi::new(),
+                    search: None,
+                    last_query: String::new(),
+                    nav: None,
                 }
             }
             Err(_) => panic!("Could not open tty."),
@@ -144,7 +269,15 @@ impl StdPrompt {
 
         // initialize termios settings
         pr.out_tcflag = pr.termios.c_lflag;
-        pr.in_tcflag = pr.out_tcflag & !(ECHO | ECHONL | ICANON | IEXTEN);
+        // ^C and the like are keys to the editor, not signals
+        pr.in_tcflag = pr.out_tcflag & !(ECHO | ECHONL | ICANON | IEXTEN | ISIG);
+        // ^S is for searching, not flow control
+        pr.out_iflag = pr.termios.c_iflag;
+        pr.in_iflag = pr.out_iflag & !IXON;
+
+        if let Err(e) = posix::catch_resize() {
+            warn!("Could not watch for the terminal being resized: {}", e);
+        }
 
         // return
         pr
@@ -152,274 +285,685 @@ impl StdPrompt {
 
     fn prep_term(&mut self) {
         self.termios.c_lflag = self.in_tcflag;
+        self.termios.c_iflag = self.in_iflag;
         tcsetattr(self.term_fi.as_raw_fd(), TCSAFLUSH, &self.termios).unwrap();
     }
 
     fn unprep_term(&mut self) {
         self.termios.c_lflag = self.out_tcflag;
+        self.termios.c_iflag = self.out_iflag;
         tcsetattr(self.term_fi.as_raw_fd(), TCSAFLUSH, &self.termios).unwrap();
     }
 
-    fn cpos(&mut self) -> (u8, u8) {
-        // get terminal settings correct
-        let cf = self.termios.c_lflag;
-        self.termios.c_lflag = cf & !(ECHO | CREAD | ICANON);
-        tcsetattr(self.term_fi.as_raw_fd(), TCSAFLUSH, &self.termios).unwrap();
-
-        // write terminal request
-        // FIXME: BETTER ERRORS
-        if let Err(e) = io::stdout().write("[6n".as_bytes()) {
-            println!("{}", e);
-            panic!();
-        }
-        if io::stdout().flush().is_err() {
-            panic!();
-        }
-        // read
-        let mut r = [0; 10];
-        if io::stdin().read(&mut r).is_err() {
-            panic!();
-        }
-        if r.len() < 6 || r[0] != 27 || r[1] != 91 {
-            panic!();
-        }
-
-        let mut sc = false;
-        let mut x = 0;
-        let mut y = 0;
-        for &c in r.iter().skip(2) {
-            if 0x30 <= c && c < 0x3A {
-                // a digit
-                if sc {
-                    y = y * 10 + (c - 0x30);
-                } else {
-                    x = x * 10 + (c - 0x30);
-                }
-            } else if c == 0x3B {
-                // ;
-                if !sc {
-                    sc = true;
-                } else {
-                    panic!();
-                }
-            } else if c == 0x52 {
-                // R
-                if sc {
-                    break;
-                } else {
-                    panic!();
-                }
-            } else {
-                println!("ack! {}", c);
-                panic!();
-            }
-        }
-
-        // return settings to normal
-        self.termios.c_lflag = cf;
-        tcsetattr(self.term_fi.as_raw_fd(), TCSAFLUSH, &self.termios).unwrap();
-
-        (x, y)
-    }
-
+    // prints the prompt at the start of the row, working out where it leaves
+    // the cursor from what it printed
     fn print_prompt(&mut self, sh: &mut Shell) {
-        print!("[G");
-        io::stdout().flush().ok().expect("Could not flush stdout");
-
         let pr_name = match self.ls {
             LineState::Normal => "_prompt",
             LineState::Comment => "_prompt_comment",
             LineState::Continue => "_prompt_continue",
         };
-        match sh.st.resolve_varish(pr_name) {
+        let text = match sh.st.resolve_varish(pr_name) {
             Some(sym::SymV::Var(s)) |
-            Some(sym::SymV::Environment(s)) => print!("{}", s),
+            Some(sym::SymV::Environment(s)) => s,
             None => {
                 let os = sh.status_code;
-                sh.input_loop(Some(vec![pr_name.to_owned()]), false);
+                let text = sh.input_loop_output(vec![pr_name.to_owned()]);
                 sh.status_code = os;
+                text
             }
-        }
+        };
 
+        let (rows, col, out) = self.term.layout(0, &text);
+        print!("{}{}{}", self.term.caps.cr, self.term.caps.clr_eos, out);
         io::stdout().flush().ok().expect("Could not flush stdout");
-        let (_, y) = self.cpos();
-        self.prompt_l = y as usize;
+        self.prompt_rows = rows;
+        self.prompt_l = col;
+        self.term.row = 0;
     }
 
-    fn get_char(&self) -> Option<Result<char>> {
-        let mut chrbuf: Vec<u8> = Vec::new();
+    fn get_size(&mut self) {
+        let cols = posix::term_size(self.term_fi.as_raw_fd()).ok().map(|(_, c)| c);
+        self.term.set_cols(cols);
+    }
 
-        // this whole thing is a nightmare, but there's no
-        // reliable io::stdin().chars() so here we are
-        for chres in io::stdin().bytes() {
-            match chres {
-                Ok(ch) => {
-                    if ch as u8 == 4 {
-                        // EOF
-                        return None;
-                    }
-                    // TODO: this is broken!! we need to see if the next
-                    // char is an ok char to start with before saying this one
-                    // is ok... UTF-8 is a bit of a nightmare
-                    chrbuf.push(ch);
-                    if let Ok(chr) = str::from_utf8(&chrbuf) {
-                        return Some(Ok(chr.chars().next().unwrap()));
-                    }
-                }
-                Err(e) => {
-                    return Some(Err(e));
+    // the terminal's been resized, and may have rewrapped what was drawn
+    fn resize(&mut self, sh: &mut Shell) {
+        self.get_size();
+        let idx = self.ed.idx;
+        self.term.row = self.term.layout(self.prompt_l, &self.ed.buf[..idx]).0;
+        self.go_top();
+        if self.search.is_some() {
+            self.search_print(sh);
+        } else {
+            self.print_prompt(sh);
+            self.reprint();
+        }
+    }
+
+    // stdin is read unbuffered, so we can tell whether more input is waiting
+    fn get_byte(&mut self) -> Option<Result<u8>> {
+        if let Some(b) = self.unread.take() {
+            return Some(Ok(b));
+        }
+        loop {
+            let mut b = [0; 1];
+            match posix::read_fd(0, &mut b) {
+                Ok(0) => return None,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Ok(_) => return Some(Ok(b[0])),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    // there's no reliable io::stdin().chars(), so we put chars together
+    // ourselves; anything that isn't UTF-8 comes out as U+FFFD
+    fn get_char(&mut self) -> Option<Result<char>> {
+        let first = match self.get_byte() {
+            Some(Ok(b)) => b,
+            Some(Err(e)) => return Some(Err(e)),
+            None => return None,
+        };
+        // the first byte says how many follow
+        let len = match first {
+            0x00..=0x7f => 1,
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ => return Some(Ok('\u{fffd}')),
+        };
+
+        let mut chrbuf = vec![first];
+        while chrbuf.len() < len {
+            match self.get_byte() {
+                Some(Ok(b)) if b & 0xc0 == 0x80 => chrbuf.push(b),
+                // the char was cut short; this byte starts the next one
+                Some(Ok(b)) => {
+                    self.unread = Some(b);
+                    return Some(Ok('\u{fffd}'));
                 }
+                Some(Err(e)) => return Some(Err(e)),
+                None => return Some(Ok('\u{fffd}')),
             }
         }
+        Some(Ok(str::from_utf8(&chrbuf).ok().and_then(|s| s.chars().next()).unwrap_or('\u{fffd}')))
+    }
 
-        // if we're *here*, we've hit EOF, and need to handle that case.
-        None
+    fn keymap<'a>(&self, sh: &'a Shell) -> &'a Keymap {
+        if !self.vi_mode {
+            &sh.km.emacs
+        } else if self.vi.insert {
+            &sh.km.vi_insert
+        } else {
+            &sh.km.vi_command
+        }
     }
 
-    fn ansi(&mut self, ch: char) -> Option<String> {
-        let len = self.ansi.len();
-        if len == 0 && ch == '' {
-            self.ansi.push(ch);
-            return None;
-        } else if len == 1 && ch == '[' {
-            self.ansi.push(ch);
-            return None;
-        } else if len >= 2 && ((ch as u8) < 64 || (ch as u8) > 126) {
-            self.ansi.push(ch);
+    // whether the keys typed so far could go on to be a longer key: the start of
+    // an escape sequence, or of keys bound in the keymap in use
+    fn awaits_more(&self, sh: &Shell) -> bool {
+        if escape_len(&self.keys).is_none() {
+            return true;
+        }
+        if self.search.is_some() {
+            return false;
+        }
+        let keys = &self.keys;
+        self.keymap(sh).keys().any(|k| k.len() > keys.len() && k.starts_with(keys as &str))
+    }
+
+    // whether the keys typed so far are waiting on an escape sequence's end
+    fn in_escape(&self) -> bool {
+        self.keys != "\x1b" && escape_len(&self.keys).is_none()
+    }
+
+    // takes the next key from those typed.  if more is coming, only a whole key
+    // will do; otherwise keys which went astray are split up, with the longest
+    // part of them which is bound going first
+    fn take_key(&mut self, sh: &Shell, more: bool) -> Option<String> {
+        if self.keys.is_empty() || (more && self.awaits_more(sh)) {
             return None;
         }
+        let n = {
+            let km = self.keymap(sh);
+            let keys = &self.keys;
+            let bound = keys.char_indices()
+                .map(|(i, c)| i + c.len_utf8())
+                .rfind(|&i| km.contains_key(&keys[..i]))
+                .unwrap_or(0);
+            let first = keys.chars().next().map(|c| c.len_utf8()).unwrap_or(0);
+            cmp::max(bound, cmp::max(escape_len(keys).unwrap_or(0), first))
+        };
+        let rest = self.keys.split_off(n);
+        Some(mem::replace(&mut self.keys, rest))
+    }
 
-        let mut ret = self.ansi.clone();
-        ret.push(ch);
-        self.ansi.clear();
+    // moves the cursor up to the start of the prompt, to print it over
+    fn go_top(&mut self) {
+        let rows = self.term.row + self.prompt_rows;
+        print!("{}{}", self.term.caps.up(rows), self.term.caps.cr);
+        self.term.row = 0;
+    }
 
-        Some(ret)
+    // moves the cursor down to the last row drawn, to print below the line
+    fn go_end(&mut self) {
+        let (end, col, _) = self.term.layout(self.prompt_l, &self.ed.buf);
+        self.term.move_to(end, col);
+        io::stdout().flush().expect("Could not flush stdout");
     }
 
-    fn reprint_cursor(&self) {
-        print!("[{}G", self.prompt_l + self.idx);
-        io::stdout().flush().ok().expect("Could not flush stdout");
+    // draws text from column start of the prompt's last row on, with the
+    // cursor at byte cursor of it
+    fn draw(&mut self, start: usize, text: &str, cursor: usize) {
+        self.term.draw(start, text, cursor);
+        io::stdout().flush().expect("Could not flush stdout");
     }
 
-    fn reprint(&self) {
-        print!("[{}G{}[K", self.prompt_l, self.buf);
-        self.reprint_cursor();
+    fn reprint_cursor(&mut self) {
+        let (row, col, _) = self.term.layout(self.prompt_l, &self.ed.buf[..self.ed.idx]);
+        self.term.move_to(row, col);
+        io::stdout().flush().expect("Could not flush stdout");
     }
 
-    fn interp(&mut self, input: &str, sh: &mut Shell) -> bool {
-        if input != "\t" {
-            self.print_multi = false;
-        }
-        match input {
-            "\n" => return true,
-            "\t" => {
-                let bufclone = self.buf.clone();
-                let (pre, post) = bufclone.split_at(self.idx);
-                let (pre, wd_pre) = pre.split_at(match pre.rfind(char::is_whitespace) {
-                    Some(n) => n + 1,
-                    None => 0,
-                });
-                let new_pre = complete(&wd_pre, &sh.st, pre.is_empty(), self.print_multi);
-                self.buf = pre.to_string();
-                self.buf.push_str(&new_pre);
-                self.buf.push_str(post);
-                self.idx = pre.len() + new_pre.len();
-                self.print_multi = true;
-                // TODO: get rid of this when we improve interactive printing
-                self.print_prompt(sh); // in case complete() printed options
-                self.reprint();
+    fn reprint(&mut self) {
+        let buf = self.ed.buf.clone();
+        let (start, idx) = (self.prompt_l, self.ed.idx);
+        self.draw(start, &buf, idx);
+    }
+
+    fn search_start(&mut self, sh: &mut Shell, older: bool) {
+        let pos = sh.ht.hist_pos();
+        self.search = Some(HistSearch {
+            query: String::new(),
+            older,
+            start: if older { pos } else { pos.saturating_sub(2) },
+            at: None,
+            failed: false,
+            saved: self.ed.buf.clone(),
+            saved_idx: self.ed.idx,
+        });
+        self.search_print(sh);
+    }
+
+    fn search_find(&mut self, sh: &mut Shell, from: Option<usize>) {
+        // a search is always for somewhere in the line, fuzzily if so asked
+        let m = match hist_match() {
+            Some(Match::Fuzzy) => Match::Fuzzy,
+            _ => Match::Substring,
+        };
+        if let Some(ref mut s) = self.search {
+            if s.query.is_empty() {
+                s.at = None;
+                s.failed = false;
+                return;
             }
-            "[3~" => {
-                if self.buf.len() > 0 && self.idx < self.buf.len() {
-                    self.buf.remove(self.idx);
-                    self.reprint();
+            let found = match from {
+                Some(from) => sh.ht.hist_find(&s.query, from, s.older, m),
+                None => None,
+            };
+            match found {
+                Some(m) => {
+                    s.at = Some(m);
+                    s.failed = false;
                 }
+                None => s.failed = true,
             }
-            "[A" => {
-                if let Some(nl) = sh.ht.hist_up() {
-                    let at_end = self.idx == self.buf.len();
-                    self.buf = nl.to_string();
-                    if at_end || self.buf.len() < self.idx {
-                        self.idx = self.buf.len();
-                    }
-                    self.reprint();
-                }
+        }
+    }
+
+    // ^R/^S again: look further along, in the given direction
+    fn search_next(&mut self, sh: &mut Shell, older: bool) {
+        let from = {
+            let s = self.search.as_mut().unwrap();
+            if s.query.is_empty() {
+                s.query = self.last_query.clone();
             }
-            "[B" => {
-                if let Some(nl) = sh.ht.hist_down() {
-                    let at_end = self.idx == self.buf.len();
-                    self.buf = nl.to_string();
-                    if at_end || self.buf.len() < self.idx {
-                        self.idx = self.buf.len();
+            s.older = older;
+            match s.at {
+                Some((i, _)) if older => Some(i + 1),
+                Some((i, _)) => i.checked_sub(1),
+                None => Some(s.start),
+            }
+        };
+        self.search_find(sh, from);
+        self.search_print(sh);
+    }
+
+    fn search_print(&mut self, sh: &mut Shell) {
+        let (text, cursor) = {
+            let s = self.search.as_ref().unwrap();
+            let label = format!("({}{}i-search)'{}': ",
+                                if s.failed { "failed " } else { "" },
+                                if s.older { "reverse-" } else { "" },
+                                s.query);
+            let (line, off) = match s.at {
+                Some((i, off)) => (sh.ht.hist_get(i).unwrap_or(""), off),
+                None => (&s.saved as &str, s.saved_idx),
+            };
+            (format!("{}{}", label, line), label.len() + off)
+        };
+        self.draw(0, &text, cursor);
+    }
+
+    // Up/Down with text before the cursor: visit only the entries which match it
+    fn hist_nav(&mut self, sh: &mut Shell, older: bool) -> bool {
+        let m = match hist_match() {
+            Some(m) => m,
+            None => return false,
+        };
+        // a line edited since the last step is a new query, from the newest entry
+        let fresh = match self.nav {
+            Some((_, _, ref shown)) => sh.ht.hist_pos() == 0 || *shown != self.ed.buf,
+            None => true,
+        };
+        if fresh {
+            sh.ht.hist_reset();
+            self.nav = Some((self.ed.buf[..self.ed.idx].to_string(),
+                             self.ed.buf.clone(),
+                             self.ed.buf.clone()));
+        }
+        let (query, saved) = match self.nav {
+            Some((ref q, ref s, _)) if !q.is_empty() => (q.clone(), s.clone()),
+            _ => return false,
+        };
+
+        let mut from = if older {
+            Some(sh.ht.hist_pos())
+        } else {
+            sh.ht.hist_pos().checked_sub(2)
+        };
+        loop {
+            match from.and_then(|f| sh.ht.hist_find(&query, f, older, m)) {
+                Some((i, off)) => {
+                    // an entry the same as the line already there is no help
+                    if sh.ht.hist_get(i) == Some(&self.ed.buf) {
+                        from = if older { Some(i + 1) } else { i.checked_sub(1) };
+                        continue;
                     }
-                    self.reprint();
+                    self.ed.buf = sh.ht.hist_get(i).unwrap_or("").to_string();
+                    // a fuzzy match has no one place the query ends
+                    self.ed.idx = match m {
+                        Match::Fuzzy => self.ed.buf.len(),
+                        _ => off + query.len(),
+                    };
+                    sh.ht.hist_goto(i);
                 }
-            }
-            "[C" => {
-                if self.idx < self.buf.len() {
-                    self.idx += 1;
-                    self.reprint_cursor();
+                // past the newest match, back to what was typed
+                None if !older && sh.ht.hist_pos() > 0 => {
+                    self.ed.buf = saved;
+                    self.ed.idx = query.len();
+                    sh.ht.hist_reset();
                 }
+                None => {}
             }
-            "[D" => {
-                if self.idx > 0 {
-                    self.idx -= 1;
-                    self.reprint_cursor();
+            break;
+        }
+        if let Some((_, _, ref mut shown)) = self.nav {
+            *shown = self.ed.buf.clone();
+        }
+        self.reprint();
+        true
+    }
+
+    // leaves the search, taking the line matched if accept, or else going
+    // back to the line from before
+    fn search_end(&mut self, sh: &mut Shell, accept: bool) {
+        if let Some(s) = self.search.take() {
+            if !s.query.is_empty() {
+                self.last_query = s.query;
+            }
+            match s.at {
+                Some((i, off)) if accept => {
+                    self.ed.buf = sh.ht.hist_get(i).unwrap_or("").to_string();
+                    self.ed.idx = off;
+                    sh.ht.hist_goto(i);
+                }
+                _ => {
+                    self.ed.buf = s.saved;
+                    self.ed.idx = s.saved_idx;
                 }
             }
+            self.go_top();
+            self.print_prompt(sh);
+            self.reprint();
+        }
+    }
+
+    // handles input while searching; None means the search is over, and the
+    // input should be handled as usual
+    fn search_interp(&mut self, input: &str, sh: &mut Shell) -> Option<bool> {
+        match input {
+            "\u{0012}" => self.search_next(sh, true),
+            "\u{0013}" => self.search_next(sh, false),
+            "\u{0007}" => self.search_end(sh, false),
+            "\x1b" => self.search_end(sh, true),
+            "\n" => {
+                self.search_end(sh, true);
+                return Some(true);
+            }
             "\x7F" => {
-                if self.buf.len() > 0 && self.idx > 0 {
-                    self.buf.remove(self.idx - 1);
-                    self.idx -= 1;
-                    self.reprint();
-                }
+                let start = {
+                    let s = self.search.as_mut().unwrap();
+                    s.query.pop();
+                    s.at = None;
+                    s.start
+                };
+                self.search_find(sh, Some(start));
+                self.search_print(sh);
+            }
+            _ if input.starts_with('\x1b') || input.chars().any(char::is_control) => {
+                self.search_end(sh, true);
+                return None;
+            }
+            _ => {
+                let from = {
+                    let s = self.search.as_mut().unwrap();
+                    s.query.push_str(input);
+                    s.at.map(|(i, _)| i).unwrap_or(s.start)
+                };
+                self.search_find(sh, Some(from));
+                self.search_print(sh);
             }
-            "\u{0001}" => {
-                self.idx = 0;
+        }
+        Some(false)
+    }
+
+    // steps through the history, all of it or (see hist_nav) what matches
+    fn hist_step(&mut self, sh: &mut Shell, older: bool) {
+        if self.hist_nav(sh, older) {
+            return;
+        }
+        let nl = if older {
+            sh.ht.hist_up()
+        } else {
+            sh.ht.hist_down()
+        };
+        if let Some(nl) = nl {
+            let at_end = self.ed.idx == self.ed.buf.len();
+            self.ed.buf = nl.to_string();
+            if at_end || self.ed.buf.len() < self.ed.idx || !self.ed.is_boundary(self.ed.idx) {
+                self.ed.idx = self.ed.buf.len();
+            }
+            self.reprint();
+        }
+    }
+
+    fn complete(&mut self, sh: &mut Shell) {
+        let bufclone = self.ed.buf.clone();
+        let (pre, post) = bufclone.split_at(self.ed.idx);
+        let (pre, wd_pre) = pre.split_at(match pre.char_indices().rev().find(|&(_, c)| c.is_whitespace()) {
+            Some((n, c)) => n + c.len_utf8(),
+            None => 0,
+        });
+        let first_word = pre.rsplit('\n').next().unwrap_or("").trim().is_empty();
+        let listing = self.print_multi;
+        if listing {
+            // any options are listed below the line
+            self.go_end();
+        }
+        let new_pre = complete(wd_pre, &sh.st, first_word, self.print_multi);
+        self.ed.buf = pre.to_string();
+        self.ed.buf.push_str(&new_pre);
+        self.ed.buf.push_str(post);
+        self.ed.idx = pre.len() + new_pre.len();
+        self.print_multi = true;
+        // TODO: get rid of this when we improve interactive printing
+        if listing {
+            self.print_prompt(sh); // in case complete() printed options
+        }
+        self.reprint();
+    }
+
+    // tells the prompt which vi mode we're in, through _vi_mode
+    fn set_mode_var(&self, sh: &mut Shell) {
+        let mode = if !self.vi_mode {
+            ""
+        } else if self.vi.insert {
+            "insert"
+        } else {
+            "normal"
+        };
+        let _ = sh.st.set_scope("_vi_mode", mode.to_string(), ScopeSpec::Global);
+    }
+
+    fn mode_changed(&mut self, sh: &mut Shell) {
+        self.set_mode_var(sh);
+        self.go_top();
+        self.print_prompt(sh);
+        self.reprint();
+    }
+
+    // a key in vi's normal mode
+    fn vi_interp(&mut self, input: &str, sh: &mut Shell) -> KeyRes {
+        if !self.vi.is_pending() || input.chars().any(char::is_control) {
+            let r = match sh.km.vi_command.get(input).cloned() {
+                Some(Binding::Cmd(cmd)) => Some(self.dispatch(cmd, input, sh)),
+                Some(Binding::Fn(f)) => Some(self.run_fn(&f, sh)),
+                None => None,
+            };
+            if let Some(r) = r {
+                self.vi.clamp(&mut self.ed);
                 self.reprint_cursor();
+                return r;
             }
-            "\u{0005}" => {
-                self.idx = self.buf.len();
+        }
+
+        match self.vi.normal_key(&mut self.ed, input) {
+            ViAction::Done => self.reprint(),
+            ViAction::Pending => {}
+            ViAction::Mode => self.mode_changed(sh),
+            ViAction::Run(cmd) => {
+                let r = self.dispatch(cmd, input, sh);
+                self.vi.clamp(&mut self.ed);
                 self.reprint_cursor();
+                return r;
             }
-            _ => {
-                // this is bad but the case where it is bad would also be bad
-                // on the part of the user... so we'll call it a draw for now.
-                for ch in input.chars() {
-                    // print!("{}-", ch as u8);
-                    self.buf.insert(self.idx, ch);
-                    self.idx += 1;
+            ViAction::Bad => {
+                self.term.bell();
+                io::stdout().flush().expect("Could not flush stdout");
+            }
+        }
+        KeyRes::More
+    }
+
+    fn interp(&mut self, input: &str, sh: &mut Shell) -> KeyRes {
+        if self.search.is_some() {
+            if let Some(r) = self.search_interp(input, sh) {
+                return if r { KeyRes::Line } else { KeyRes::More };
+            }
+        }
+        if self.vi_mode && !self.vi.insert {
+            return self.vi_interp(input, sh);
+        }
+
+        let cmd = match self.keymap(sh).get(input).cloned() {
+            Some(Binding::Cmd(cmd)) => cmd,
+            Some(Binding::Fn(f)) => return self.run_fn(&f, sh),
+            // unbound keys (a lone escape, for one) do nothing
+            None if input.chars().any(char::is_control) => return KeyRes::More,
+            None => EditCmd::SelfInsert,
+        };
+        if self.vi_mode {
+            match cmd {
+                EditCmd::SelfInsert => self.vi.note_insert(input),
+                EditCmd::BackwardDeleteChar => self.vi.note_backspace(),
+                _ => {}
+            }
+        }
+        self.dispatch(cmd, input, sh)
+    }
+
+    // runs a fn bound to a key.  it's given the line and the cursor (in
+    // chars) in _line and _cursor, and we go on with what it leaves there
+    fn run_fn(&mut self, name: &str, sh: &mut Shell) -> KeyRes {
+        let cursor = self.ed.char_pos(self.ed.idx);
+        let _ = sh.st.set_scope("_line", self.ed.buf.clone(), ScopeSpec::Global);
+        let _ = sh.st.set_scope("_cursor", cursor.to_string(), ScopeSpec::Global);
+
+        // the fn gets the terminal as usual, so it can run e.g. a fuzzy finder
+        self.unprep_term();
+        let os = sh.status_code;
+        sh.input_loop(Some(vec![name.to_owned()]), false);
+        sh.status_code = os;
+        self.prep_term();
+
+        let var = |sh: &mut Shell, k: &str| match sh.st.resolve_varish(k) {
+            Some(sym::SymV::Var(s)) |
+            Some(sym::SymV::Environment(s)) => s,
+            None => String::new(),
+        };
+        let line = var(sh, "_line");
+        let cursor = var(sh, "_cursor").parse::<usize>().unwrap_or(cursor);
+        let _ = sh.st.set_scope("_line", String::new(), ScopeSpec::Global);
+        let _ = sh.st.set_scope("_cursor", String::new(), ScopeSpec::Global);
+
+        if line != self.ed.buf {
+            self.ed.snapshot();
+            self.ed.buf = line;
+        }
+        self.ed.idx = self.ed.char_idx(cursor);
+        self.go_top();
+        self.print_prompt(sh);
+        self.reprint();
+        KeyRes::More
+    }
+
+    // runs cmd, whichever keys it was bound to
+    fn dispatch(&mut self, cmd: EditCmd, input: &str, sh: &mut Shell) -> KeyRes {
+        if cmd != EditCmd::Complete {
+            self.print_multi = false;
+        }
+
+        if cmd == EditCmd::SelfInsert {
+            self.ed.insert(input);
+            self.reprint();
+            return KeyRes::More;
+        }
+        if self.ed.run(cmd) {
+            self.reprint();
+            return KeyRes::More;
+        }
+        match cmd {
+            EditCmd::Accept => {
+                // a block still open goes on to another line
+                if self.ls == LineState::Normal && !lexer::is_complete(&self.ed.buf) {
+                    self.ed.insert("\n");
+                    self.reprint();
+                } else {
+                    return KeyRes::Line;
                 }
-                // println!("");
+            }
+            EditCmd::Complete => self.complete(sh),
+            EditCmd::DeleteCharOrEof => {
+                if self.ed.buf.is_empty() {
+                    return KeyRes::Eof;
+                }
+                self.ed.run(EditCmd::DeleteChar);
+                self.reprint();
+            }
+            // in a multi-line buffer, up and down move between its lines first
+            EditCmd::PreviousHistory if self.ed.line_up() => self.reprint_cursor(),
+            EditCmd::NextHistory if self.ed.line_down() => self.reprint_cursor(),
+            EditCmd::PreviousHistory => self.hist_step(sh, true),
+            EditCmd::NextHistory => self.hist_step(sh, false),
+            EditCmd::ReverseSearch => self.search_start(sh, true),
+            EditCmd::ForwardSearch => self.search_start(sh, false),
+            EditCmd::ClearScreen => {
+                print!("{}", self.term.caps.clear);
+                self.print_prompt(sh);
                 self.reprint();
             }
+            EditCmd::Abandon => {
+                self.go_end();
+                println!("^C");
+                self.ed.reset();
+                self.nav = None;
+                sh.ht.hist_reset();
+                self.vi.start_line();
+                self.set_mode_var(sh);
+                self.print_prompt(sh);
+            }
+            EditCmd::ViCommandMode if self.vi_mode => {
+                self.vi.enter_normal(&mut self.ed);
+                self.mode_changed(sh);
+            }
+            _ => {}
         }
 
-        false
+        KeyRes::More
     }
 }
 
 impl Prompt for StdPrompt {
+    fn interactive(&self) -> bool {
+        true
+    }
+
     // functionality to add:
     //  - proper POSIX terminal control
     //  - coloration (?)
     //  - ANSI code interpretation
     fn prompt(&mut self, sh: &mut Shell) -> Option<Result<String>> {
+        // the shell takes a multi-line buffer a line at a time
+        if !self.queued.is_empty() {
+            return Some(Ok(self.queued.remove(0)));
+        }
+
         self.ls = sh.ls;
+        posix::resized();
+        self.get_size();
+        self.vi_mode = opts::get("__tin_editmode").map(|m| m == "vi").unwrap_or(false);
+        self.vi.start_line();
+        self.set_mode_var(sh);
         self.print_prompt(sh);
         self.prep_term();
 
-        self.buf.clear();
-        self.idx = 0;
+        self.ed.reset();
+        self.search = None;
+        self.nav = None;
 
         loop {
+            // wait for a key, keeping up with the terminal's size meanwhile
+            while self.unread.is_none() {
+                match posix::poll_read(0, -1) {
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                        if posix::resized() {
+                            self.resize(sh);
+                        }
+                    }
+                    _ => break,
+                }
+            }
+
             match self.get_char() {
                 Some(Ok(ch)) => {
-                    if let Some(res) = self.ansi(ch) {
-                        if self.interp(&res, sh) {
+                    self.keys.push(ch);
+                    // keys which could be the start of longer ones are only
+                    // taken alone if nothing else comes soon after them (an
+                    // escape sequence being sent can't be, though)
+                    let mut more = false;
+                    if self.awaits_more(sh) {
+                        let wait = if self.keys == "\x1b" { 25 } else { 500 };
+                        more = self.in_escape() || self.unread.is_some() ||
+                               posix::poll_read(0, wait).unwrap_or(true);
+                    }
+
+                    let mut res = KeyRes::More;
+                    while let Some(k) = self.take_key(sh, more) {
+                        res = self.interp(&k, sh);
+                        if res != KeyRes::More {
                             break;
                         }
                     }
+                    match res {
+                        KeyRes::More => {}
+                        KeyRes::Line => break,
+                        KeyRes::Eof => {
+                            self.unprep_term();
+                            return None;
+                        }
+                    }
                 }
                 Some(Err(e)) => {
                     return Some(Err(e));
@@ -430,13 +974,15 @@ impl Prompt for StdPrompt {
             }
         }
 
-        // fix this whole deal
-        self.buf.push('\n');
+        self.go_end();
         println!("");
 
         self.unprep_term();
 
-        Some(Ok(self.buf.clone()))
+        let mut lines = self.ed.buf.split('\n').map(|l| format!("{}\n", l)).collect::<Vec<_>>();
+        let first = lines.remove(0);
+        self.queued = lines;
+        Some(Ok(first))
     }
 }
 
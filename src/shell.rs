@@ -1,23 +1,43 @@
+use std::collections::HashMap;
+use std::io;
 use std::io::Read;
+use std::io::Write;
 use std::mem;
+use std::thread;
+use std::path::{Path, PathBuf};
 
 use prompt::Prompt;
 use prompt::BasicPrompt;
+use prompt::FilePrompt;
 use prompt::LineState;
 use sym::Symtable;
 use hist::Histvec;
+use editor::Binding;
+use editor::Keymaps;
 use std::process::exit;
 
+use err;
 use parser;
 use posix;
 use opts;
+use exec;
 use exec::Arg;
+use exec::Redir;
 use exec::job::Job;
 use sym::ScInter;
 use sym::ScType;
+use sym::ScopeSpec;
 
 use parser::Parser;
 
+// output collected by () is put on one line
+fn collected(mut output: String) -> String {
+    while output.ends_with('\n') {
+        output.pop();
+    }
+    output.replace('\n', " ")
+}
+
 /// terrible God object to make state accessible to everyone everywhere
 pub struct Shell {
     pub jobs: Vec<Job>,
@@ -28,6 +48,40 @@ pub struct Shell {
     pub ls: LineState,
     pub st: Symtable,
     pub ht: Histvec,
+    pub km: Keymaps,
+
+    // files currently being sourced, innermost last
+    pub srcs: Vec<PathBuf>,
+
+    // the value given by 'return -v' in the last fn to finish
+    pub ret_val: Option<String>,
+
+    // fns currently being run, innermost last, and where we are in the code
+    pub calls: Vec<Frame>,
+    pub file: Option<PathBuf>,
+    pub lineno: usize,
+
+    // blocks to run on signals (by name) and on 'EXIT' and 'ERR'
+    pub traps: HashMap<String, Vec<String>>,
+    pub in_trap: bool,
+
+    // how many runs of input_loop_output are collecting what's printed
+    pub capturing: usize,
+}
+
+/// A fn call in progress: the fn, and where it was called from.
+pub struct Frame {
+    pub name: String,
+    pub file: Option<PathBuf>,
+    pub line: usize,
+}
+
+// describes a place in the code being run
+fn location(file: &Option<PathBuf>, line: usize) -> String {
+    match *file {
+        Some(ref f) => format!("{}:{}", f.display(), line),
+        None => format!("line {}", line),
+    }
 }
 
 impl Shell {
@@ -76,10 +130,15 @@ impl Shell {
     pub fn input_loop(&mut self, mut in_lines: Option<Vec<String>>, hist: bool) -> Option<ScInter> {
         // saves previous inputs in the case of LineState::Continue
         let mut input_buf = String::new();
+        // lines we're given start where the code which gave them is
+        let saved_line = self.lineno;
+        let mut line = if in_lines.is_some() { self.lineno } else { 1 };
+        let mut next_line = line;
         // saves "future" inputs in the case of multi-line input
         let mut next_buf: Option<String> = None;
 
         let mut ps = Parser::new();
+        let pins = self.st.pin_depth();
 
         loop {
             // get input
@@ -95,6 +154,28 @@ impl Shell {
                 let saved_ls = self.ls;
                 match self.get_line(&mut in_lines) {
                     Some(prompt_in) => {
+                        line = next_line;
+                        next_line += 1;
+                        // only what's typed at the prompt, not the tinrc, is expanded
+                        let prompt_in = if hist && in_lines.is_none() && self.pr.interactive() &&
+                                           opts::is_set("__tin_histexpand") &&
+                                           prompt_in.contains('!') {
+                            match parser::hist_expand(&self.ht, &prompt_in) {
+                                Ok(l) => {
+                                    // show what's actually being run
+                                    if l != prompt_in {
+                                        print!("{}", l);
+                                    }
+                                    l
+                                }
+                                Err(e) => {
+                                    warn!("history: {}", e);
+                                    continue;
+                                }
+                            }
+                        } else {
+                            prompt_in
+                        };
                         // we needed more and we got more
                         let (spl_input, spl_next_buf) = parser::spl_line(&prompt_in);
                         input = spl_input;
@@ -107,6 +188,11 @@ impl Shell {
                 self.ls = saved_ls;
             }
 
+            // blank lines aren't commands, and shouldn't touch the status
+            if self.ls == LineState::Normal && input.trim().is_empty() {
+                continue;
+            }
+
             // do stuff with input
             self.ls = match self.ls {
                 LineState::Comment => {
@@ -118,10 +204,17 @@ impl Shell {
                     }
                 }
                 LineState::Normal | LineState::Continue => {
+                    if input_buf.is_empty() {
+                        self.lineno = line;
+                        self.st.pin_start();
+                        // only what this statement warns about counts for it
+                        err::take_warned();
+                    }
                     if !input_buf.is_empty() {
                         input_buf.push('\n');
                     }
-                    input_buf.push_str(&input);
+                    // each line is remembered once, without the newline it came with
+                    input_buf.push_str(input.trim_end_matches('\n'));
                     if self.ls == LineState::Normal {
                         ps.reset();
                     }
@@ -133,9 +226,26 @@ impl Shell {
                             if hist {
                                 self.ht.hist_add(input_buf.trim());
                             }
+                            // a block's own commands have had their chance to trip ERR
+                            let blk = t_job.runs_block();
                             self.exec(t_job);
+                            // the innermost statement to see an error keeps the trace
+                            if err::take_warned() {
+                                self.save_trace();
+                            }
+                            if hist {
+                                self.ht.hist_finish(self.status_code);
+                            }
+                            // and a () capture leaves ERR to the statement it's part of, or
+                            // the trap's output would be captured along with the command's
+                            if self.status_code != 0 && !blk && !self.in_trap && self.calls.is_empty() &&
+                               self.capturing == 0 && !self.st.subsh {
+                                self.run_trap("ERR");
+                            }
                         }
+                        self.run_pending_traps();
                         input_buf = String::new();
+                        self.end_statement(pins);
                     }
 
                     ls
@@ -143,9 +253,13 @@ impl Shell {
             };
 
             if let Some(sci) = self.st.pull_sc_inter() {
+                self.lineno = saved_line;
+                self.end_statement(pins);
                 return Some(sci);
             }
         }
+        self.lineno = saved_line;
+        self.end_statement(pins);
 
         if self.ls != LineState::Normal {
             // warn or info?
@@ -155,6 +269,23 @@ impl Shell {
         None
     }
 
+    // unpins what the statements being ended made, and cleans up after them if
+    // it's time to
+    fn end_statement(&mut self, pins: usize) {
+        let keep = self.capturing > 0;
+        self.st.pin_end(pins, keep);
+        if self.st.should_collect() {
+            let mut held = self.ret_val.iter().cloned().collect::<Vec<_>>();
+            for km in &[&self.km.emacs, &self.km.vi_insert, &self.km.vi_command] {
+                held.extend(km.values().filter_map(|b| match *b {
+                    Binding::Fn(ref f) => Some(f.clone()),
+                    _ => None,
+                }));
+            }
+            self.st.collect(&held);
+        }
+    }
+
     pub fn input_loop_collect(&mut self, in_lines: Option<Vec<String>>) -> String {
         let (mut re, wr) = posix::pipe().unwrap();
         match posix::fork(false, None) {
@@ -164,6 +295,7 @@ impl Shell {
             }
             Ok(None) => {
                 self.st.subsh = true;
+                self.drop_traps();
                 re.close();
                 if let Err(e) = posix::set_stdout(wr) {
                     err!("Could not set stdout: {}", e);
@@ -177,12 +309,7 @@ impl Shell {
                 wr.close();
                 let mut output = String::new();
                 match re.read_to_string(&mut output) {
-                    Ok(_len) => {
-                        while output.ends_with('\n') {
-                            output.pop();
-                        }
-                        output = output.replace('\n', " ");
-                    }
+                    Ok(_len) => output = collected(output),
                     Err(e) => warn!("Error reading from child: {}", e),
                 }
                 if let Err(e) = posix::wait_pid(&ch) {
@@ -194,6 +321,166 @@ impl Shell {
         }
     }
 
+    /// Runs the trap set for name (a signal, 'EXIT' or 'ERR'), if there is one,
+    /// leaving the status as it was before.
+    pub fn run_trap(&mut self, name: &str) {
+        let lines = match self.traps.get(name) {
+            Some(l) => l.clone(),
+            None => return,
+        };
+
+        let status = self.status_code;
+        let in_trap = mem::replace(&mut self.in_trap, true);
+        self.block_exec(ScType::Default, lines);
+        self.in_trap = in_trap;
+        self.status_code = status;
+    }
+
+    // runs the traps of any signals caught since we last looked
+    fn run_pending_traps(&mut self) {
+        if self.in_trap {
+            return;
+        }
+        for sig in posix::pending_signals() {
+            if let Some(name) = posix::signal_name(sig) {
+                self.run_trap(name);
+            }
+        }
+    }
+
+    /// Called in a child forked off to run part of a pipeline.  Its traps are
+    /// the parent's to run, so they're dropped, and any signals they caught go
+    /// back to how they were.
+    pub fn drop_traps(&mut self) {
+        for (name, lines) in self.traps.drain() {
+            if let Some(sig) = posix::signal_num(&name) {
+                if lines.iter().any(|l| !l.trim().is_empty()) {
+                    let _ = posix::reset_signal(sig, false);
+                }
+            }
+        }
+    }
+
+    /// Runs the EXIT trap, as the shell is on its way out.
+    pub fn exit_trap(&mut self) {
+        // subshells don't get their parent's traps
+        if self.st.subsh {
+            return;
+        }
+        // only ever once, even if the trap itself exits
+        if let Some(lines) = self.traps.remove("EXIT") {
+            let status = self.status_code;
+            self.in_trap = true;
+            self.block_exec(ScType::Default, lines);
+            self.status_code = status;
+        }
+    }
+
+    /// The tin-level stack trace: each fn being run and the line it's on,
+    /// innermost first, and then the line at the top level.
+    pub fn stack_trace(&self) -> Vec<String> {
+        let mut res = Vec::new();
+        let mut at = location(&self.file, self.lineno);
+        for fr in self.calls.iter().rev() {
+            res.push(format!("  in fn '{}' at {}", fr.name, at));
+            at = location(&fr.file, fr.line);
+        }
+        res.push(format!("  at {}", at));
+        res
+    }
+
+    /// Keeps the stack trace of an error in a fn around in _trace, to be
+    /// looked at after the fact.  Every statement which gives a warning does
+    /// this, so the trace is that of the innermost one.
+    pub fn save_trace(&mut self) {
+        if !self.calls.is_empty() {
+            let trace = self.stack_trace().join("\n");
+            let _ = self.st.set_scope("_trace", trace, ScopeSpec::Global);
+        }
+    }
+
+    /// Like input_loop_collect, but runs the lines in this shell rather than in a
+    /// child, so that whatever they set sticks around.  If a fn run by the lines
+    /// gives a value with 'return -v', that is collected instead of the output,
+    /// which is printed as usual.
+    pub fn input_loop_capture(&mut self, in_lines: Vec<String>) -> String {
+        self.ret_val = None;
+        let output = self.input_loop_output(in_lines);
+
+        match self.ret_val.take() {
+            Some(v) => {
+                print!("{}", output);
+                v
+            }
+            None => collected(output),
+        }
+    }
+
+    /// Runs the lines in this shell, giving back all they wrote to stdout just as
+    /// it was written.
+    pub fn input_loop_output(&mut self, in_lines: Vec<String>) -> String {
+        let (mut re, wr) = match posix::pipe() {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("Could not create pipe: {}", e);
+                return String::new();
+            }
+        };
+
+        let _ = io::stdout().flush();
+        let wr = wr.into_raw();
+        let undo = exec::redirect_shell(vec![Redir::RdFdOut(1, wr)], true);
+        if wr != 1 {
+            let _ = posix::close_fd(wr);
+        }
+        let undo = match undo {
+            Ok(u) => u,
+            Err(e) => {
+                warn!("Could not redirect stdout: {}", e);
+                return String::new();
+            }
+        };
+
+        // drain the pipe as we go, so the lines can't fill it up and block
+        let reader = thread::spawn(move || {
+            let mut buf = Vec::new();
+            re.read_to_end(&mut buf).map(|_| buf)
+        });
+
+        self.capturing += 1;
+        self.input_loop(Some(in_lines), false);
+        self.capturing -= 1;
+
+        let _ = io::stdout().flush();
+        if let Err(e) = exec::redirect_shell(undo, false) {
+            err!("Could not restore stdout: {}", e);
+        }
+
+        match reader.join() {
+            Ok(Ok(buf)) => String::from_utf8_lossy(&buf).into_owned(),
+            Ok(Err(e)) => {
+                warn!("Error reading output: {}", e);
+                String::new()
+            }
+            Err(_) => String::new(),
+        }
+    }
+
+    /// Runs the lines of a file in this shell, rather than in a child.
+    pub fn file_exec(&mut self, path: &Path) -> io::Result<Option<ScInter>> {
+        let p = try!(FilePrompt::new(&path.to_string_lossy()));
+        let pr = mem::replace(&mut self.pr, Box::new(p));
+        self.srcs.push(path.to_path_buf());
+        let file = self.file.replace(path.to_path_buf());
+
+        let x = self.input_loop(None, false);
+
+        self.file = file;
+        self.srcs.pop();
+        self.pr = pr;
+        Ok(x)
+    }
+
     pub fn block_exec(&mut self, sc_type: ScType, bv: Vec<String>) -> (Option<ScInter>, i32) {
         self.st.new_scope(sc_type);
         let x = self.input_loop(Some(bv), false);
@@ -1,20 +1,30 @@
 #![allow(dead_code)]
 
 use builtins;
+use std::cell::RefCell;
+use std::cmp;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::env;
 use std::fs;
 use std::path;
 use std::mem;
+use std::os::unix::fs::PermissionsExt;
+use std::rc::Rc;
+use std::rc::Weak;
 
 use opts;
 
+extern crate regex;
+use self::regex::Regex;
 
 #[derive(PartialEq, Copy, Clone)]
 pub enum ScType {
     Fn,
     Loop,
     Global,
+    Source,
+    Closure, // the scopes a fn was defined in, shared with it while it runs
     Default,
 }
 
@@ -30,13 +40,13 @@ pub enum Sym {
     Builtin(builtins::Builtin),
     Var(String),
     Environment(String),
-    Fn(Fn),
+    Fn(Box<Fn>),
 }
 
 pub enum SymE {
     Binary(path::PathBuf),
     Builtin(builtins::Builtin),
-    Fn(Fn),
+    Fn(Box<Fn>),
 }
 
 pub enum SymV {
@@ -44,19 +54,84 @@ pub enum SymV {
     Environment(String),
 }
 
+/// What an argument must look like to be accepted by a fn parameter.
+#[derive(Clone)]
+pub enum ParamPat {
+    Int,
+    File,
+    Dir,
+    Regex(Regex),
+}
+
+/// A fn parameter, e.g. `n`, `n?`, `n=5`, `n:int` or `n~^[a-z]+$`.
+#[derive(Clone)]
+pub struct Param {
+    pub name: String,
+    pub opt: bool,
+    pub group: Option<usize>, // params in the same [] group are given all-or-nothing
+    pub default: Option<String>,
+    pub pat: Option<ParamPat>,
+}
+
+/// A fn flag, e.g. `-r/--recursive`, or `-o/--output=` for one which takes a value.
+#[derive(Clone)]
+pub struct Flag {
+    pub short: Option<char>,
+    pub long: Option<String>,
+    pub val: Option<String>, // the default value, if the flag takes one
+}
+
+impl Flag {
+    /// The name of the local var the flag is given to in the fn.
+    pub fn var(&self) -> String {
+        match self.long {
+            Some(ref l) => l.replace('-', "_"),
+            None => self.short.unwrap().to_string(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Fn {
     pub name: String,
     pub inline: bool,
-    pub args: Vec<String>,
-    pub vararg: Option<String>,
-    pub postargs: Option<Vec<String>>,
+    pub flags: Vec<Flag>,
+    pub args: Vec<Param>,
+    pub vararg: Option<Param>,
+    pub postargs: Option<Vec<Param>>,
     pub lines: Vec<String>,
+    pub doc: Option<String>, // from the comment lines at the top of the fn
+    pub module: Option<String>, // the module this fn was defined in, if any
+    pub env: Closure,
+    pub file: Option<path::PathBuf>, // where the fn was defined, for stack traces
+    pub line: usize,
 }
 
+type Vars = Rc<RefCell<HashMap<String, Val>>>;
+
+// what the names of fn handles start with
+const HANDLE_PREFIX: &str = "__fn_";
+// the fewest handles and closed-over scopes worth collecting
+const COLLECT_MIN: usize = 64;
+
+/// The scopes a fn was defined in, which it can still see (and set) when it
+/// runs, even once they've been left.
+#[derive(Clone, Default)]
+pub struct Closure(Vec<Vars>);
+
+impl Fn {
+    // whether other is this very fn, rather than one which looks like it
+    fn same(&self, other: &Fn) -> bool {
+        self.name == other.name && self.lines == other.lines &&
+        self.env.0.len() == other.env.0.len() &&
+        self.env.0.iter().zip(other.env.0.iter()).all(|(a, b)| Rc::ptr_eq(a, b))
+    }
+}
+
+#[derive(Clone)]
 enum Val {
     Var(String),
-    Fn(Fn),
+    Fn(Box<Fn>),
 }
 
 // the 'is_fn' flag enables us to check
@@ -64,9 +139,11 @@ enum Val {
 // a function -- aside from global ops, we don't want to act
 // through the function barrier.
 struct Scope {
-    vars: HashMap<String, Val>, // contains vars and also functions
+    vars: Vars, // contains vars and also functions
     sc_type: ScType,
     inter: Option<ScInter>,
+    ret_val: Option<String>, // given by 'return -v'
+    module: Option<String>, // set on the scope of a fn or file from a module
 }
 
 #[derive(PartialEq, Copy, Clone)]
@@ -81,8 +158,16 @@ pub enum ScopeSpec {
 // We can use 'static for builtins because that's what builtins are: static.
 pub struct Symtable {
     bins: HashMap<String, path::PathBuf>,
+    used: HashSet<String>, // bins which have been looked up, for 'hash'
     builtins: HashMap<&'static str, builtins::Builtin>,
     scopes: Vec<Scope>,
+    modules: HashMap<String, HashMap<String, Val>>,
+    handles: HashMap<String, Fn>, // fns which have been passed around by value
+    named: HashMap<String, String>, // the handle given to each named fn
+    next_handle: usize,
+    closed: HashMap<usize, Weak<RefCell<HashMap<String, Val>>>>, // scopes fns have closed over
+    pins: Vec<Vec<String>>, // handles which may be on their way into a var, by statement
+    collect_at: usize,
     pub subsh: bool,
 }
 
@@ -90,15 +175,25 @@ impl Symtable {
     pub fn new() -> Symtable {
         let mut st = Symtable {
             bins: HashMap::new(),
+            used: HashSet::new(),
             builtins: builtins::Builtin::map(),
             scopes: Vec::new(),
+            modules: HashMap::new(),
+            handles: HashMap::new(),
+            named: HashMap::new(),
+            next_handle: 0,
+            closed: HashMap::new(),
+            pins: Vec::new(),
+            collect_at: COLLECT_MIN,
             subsh: false,
         };
 
         st.scopes.push(Scope {
-            vars: HashMap::new(),
+            vars: Vars::default(),
             sc_type: ScType::Global,
             inter: None,
+            ret_val: None,
+            module: None,
         });
 
         st.hash_bins();
@@ -110,6 +205,14 @@ impl Symtable {
         mem::replace(&mut self.scopes.last_mut().unwrap().inter, None)
     }
 
+    /// Marks every scope but the global one to be broken out of, so that whatever
+    /// is running unwinds back to the top level.
+    pub fn sc_abort(&mut self) {
+        for sc in self.scopes.iter_mut().skip(1) {
+            sc.inter = Some(ScInter::Break);
+        }
+    }
+
     // NOTE: simply marks the relevant data structures, *does not* actually break/etc.
     pub fn sc_break(&mut self, mut d: u16) {
         let mut found = false;
@@ -156,20 +259,21 @@ impl Symtable {
         }
     }
 
-    pub fn sc_return(&mut self, retcode: i32) {
+    pub fn sc_return(&mut self, retcode: i32, val: Option<String>) {
         let mut found = false;
         for mut sc in self.scopes.iter_mut().rev() {
             sc.inter = Some(ScInter::Break);
-            if sc.sc_type == ScType::Fn {
+            if sc.sc_type == ScType::Fn || sc.sc_type == ScType::Source {
                 found = true;
                 sc.inter = Some(ScInter::Return(retcode));
+                sc.ret_val = val;
                 break;
             } else {
                 sc.inter = Some(ScInter::Break);
             }
         }
         if !found {
-            warn!("'return' command used not in a fn");
+            warn!("'return' command used not in a fn or sourced file");
 
             for mut sc in self.scopes.iter_mut() {
                 sc.inter = None;
@@ -177,6 +281,11 @@ impl Symtable {
         }
     }
 
+    /// Takes the value given to 'return -v' in the current scope, if there was one.
+    pub fn pull_ret_val(&mut self) -> Option<String> {
+        self.scopes.last_mut().unwrap().ret_val.take()
+    }
+
     pub fn set(&mut self, key: &str, val: String) -> Result<&mut Symtable, opts::OptError> {
         self.set_scope(key, val, ScopeSpec::Default)
     }
@@ -187,13 +296,31 @@ impl Symtable {
             ScopeSpec::Local => self.scopes.len() - 1,
             ScopeSpec::Environment => unreachable!(),
             ScopeSpec::Default => {
+                // a sourced file's scope only holds its arguments; anything new it
+                // sets should land in the scope of whoever sourced it
                 let len = self.scopes.len();
-                for (idx, scope) in self.scopes.iter_mut().rev().enumerate() {
-                    if scope.vars.get(key).is_some() || scope.sc_type == ScType::Fn {
-                        return len - idx - 1;
+                let mut fallback = None;
+                for idx in (0..len).rev() {
+                    let scope = &self.scopes[idx];
+                    if scope.vars.borrow().contains_key(key) {
+                        return idx;
+                    }
+                    if scope.sc_type == ScType::Fn {
+                        // a fn may set what it closes over, but anything new is its own
+                        for cl in (1..idx).rev() {
+                            if self.scopes[cl].sc_type != ScType::Closure {
+                                break;
+                            } else if self.scopes[cl].vars.borrow().contains_key(key) {
+                                return cl;
+                            }
+                        }
+                        return idx;
+                    }
+                    if fallback.is_none() && scope.sc_type != ScType::Source {
+                        fallback = Some(idx);
                     }
                 }
-                len - 1
+                fallback.unwrap_or(len - 1)
             }
         }
     }
@@ -202,7 +329,7 @@ impl Symtable {
         {
             let idx = self.scope_idx(key, sc);
             let ref mut scope = self.scopes[idx];
-            scope.vars.insert(key.to_string(), Val::Fn(val));
+            scope.vars.borrow_mut().insert(key.to_string(), Val::Fn(Box::new(val)));
         }
         self
     }
@@ -219,6 +346,9 @@ impl Symtable {
 
         if sc == ScopeSpec::Environment {
             env::set_var(key, val);
+            if key == "PATH" {
+                self.hash_bins();
+            }
             return Ok(self);
         }
 
@@ -228,9 +358,9 @@ impl Symtable {
             let ref mut scope = self.scopes[idx];
 
             if val == "" {
-                scope.vars.remove(key);
+                scope.vars.borrow_mut().remove(key);
             } else {
-                scope.vars.insert(key.to_string(), Val::Var(val));
+                scope.vars.borrow_mut().insert(key.to_string(), Val::Var(val));
             }
         }
 
@@ -238,15 +368,277 @@ impl Symtable {
     }
 
     pub fn new_scope(&mut self, sc_type: ScType) -> &mut Symtable {
+        self.new_scope_in(sc_type, None)
+    }
+
+    /// Creates a new scope whose unqualified names also resolve to those in module.
+    pub fn new_scope_in(&mut self, sc_type: ScType, module: Option<String>) -> &mut Symtable {
         self.scopes.push(Scope {
-            vars: HashMap::new(),
+            vars: Vars::default(),
             sc_type: sc_type,
             inter: None,
+            ret_val: None,
+            module,
         });
 
         self
     }
 
+    /// Returns the module whose code is currently running, if any.
+    pub fn cur_module(&self) -> Option<String> {
+        for sc in self.scopes.iter().rev() {
+            if sc.module.is_some() || sc.sc_type == ScType::Fn {
+                return sc.module.clone();
+            }
+        }
+        None
+    }
+
+    pub fn has_module(&self, name: &str) -> bool {
+        self.modules.contains_key(name)
+    }
+
+    /// Pops the current scope, keeping what was defined in it as the module name.
+    pub fn close_module(&mut self, name: &str) -> &mut Symtable {
+        if let Some(sc) = self.scopes.pop() {
+            let vars = sc.vars.borrow().clone();
+            self.modules.insert(name.to_string(), vars);
+        }
+
+        self
+    }
+
+    /// Forgets the module name, as if it had never been loaded.
+    pub fn drop_module(&mut self, name: &str) {
+        self.modules.remove(name);
+    }
+
+    /// Binds the given symbols from a module, unqualified, as if they had been set here.
+    /// Returns the names which the module does not have.
+    pub fn import_syms(&mut self, module: &str, syms: &[String]) -> Vec<String> {
+        let mut missing = Vec::new();
+        for k in syms {
+            let val = match self.modules.get(module).and_then(|m| m.get(k)) {
+                Some(v) => v.clone(),
+                None => {
+                    missing.push(k.clone());
+                    continue;
+                }
+            };
+            let idx = self.scope_idx(k, ScopeSpec::Default);
+            self.scopes[idx].vars.borrow_mut().insert(k.clone(), val);
+        }
+        missing
+    }
+
+    // looks for sym_n either as 'module.name' or in the currently running module
+    // looks sym_n up, taking the first value pick accepts: in the scopes being
+    // run in, then in the module being run (ahead of the global scope, so that
+    // a global can't shadow the module's own helpers), then in the global
+    // scope, and last as a 'module.name'
+    fn lookup<T, F>(&self, sym_n: &str, pick: F) -> Option<T>
+        where F: ::std::ops::Fn(&Val) -> Option<T>
+    {
+        for scope in self.scopes.iter().skip(1).rev() {
+            if let Some(x) = scope.vars.borrow().get(sym_n).and_then(&pick) {
+                return Some(x);
+            }
+        }
+
+        if let Some(m) = self.cur_module() {
+            if let Some(x) = self.modules.get(&m).and_then(|m| m.get(sym_n)).and_then(&pick) {
+                return Some(x);
+            }
+        }
+
+        if let Some(scope) = self.scopes.first() {
+            if let Some(x) = scope.vars.borrow().get(sym_n).and_then(&pick) {
+                return Some(x);
+            }
+        }
+
+        match sym_n.find('.') {
+            Some(n) if n > 0 => {
+                self.modules.get(&sym_n[..n]).and_then(|m| m.get(&sym_n[n + 1..])).and_then(&pick)
+            }
+            _ => None,
+        }
+    }
+
+    /// Captures the scopes being run in, for a fn defined here to close over:
+    /// those of the innermost running fn (and what it closes over), or else
+    /// everything short of the global scope.
+    pub fn capture(&mut self) -> Closure {
+        let start = match self.scopes.iter().rposition(|sc| sc.sc_type == ScType::Fn) {
+            Some(mut i) => {
+                while i > 1 && self.scopes[i - 1].sc_type == ScType::Closure {
+                    i -= 1;
+                }
+                i
+            }
+            None => 1,
+        };
+
+        let env = Closure(self.scopes[start..].iter().map(|sc| sc.vars.clone()).collect());
+        for vars in &env.0 {
+            let key = &*vars.borrow() as *const _ as usize;
+            if self.closed.get(&key).and_then(|w| w.upgrade()).is_none() {
+                self.closed.insert(key, Rc::downgrade(vars));
+            }
+        }
+        env
+    }
+
+    /// Puts the scopes a fn closes over back in place, for it to run in.
+    pub fn push_closure(&mut self, env: &Closure) -> &mut Symtable {
+        for vars in &env.0 {
+            self.scopes.push(Scope {
+                vars: vars.clone(),
+                sc_type: ScType::Closure,
+                inter: None,
+                ret_val: None,
+                module: None,
+            });
+        }
+
+        self
+    }
+
+    pub fn pop_closure(&mut self, env: &Closure) -> &mut Symtable {
+        let len = self.scopes.len();
+        self.scopes.truncate(len - env.0.len());
+
+        self
+    }
+
+    /// Gives f a handle by which it can be passed around and called, like a var.
+    /// A named fn keeps the one handle, for as long as it's the same fn.
+    pub fn fn_handle(&mut self, mut f: Fn) -> String {
+        let old = match self.named.get(&f.name) {
+            Some(h) => Some(h.clone()),
+            None if f.name.starts_with(HANDLE_PREFIX) => Some(f.name.clone()),
+            None => None,
+        };
+        if let Some(h) = old {
+            if self.handles.get(&h).map(|g| g.same(&f)).unwrap_or(false) {
+                self.pin(&h);
+                return h;
+            }
+        }
+
+        self.next_handle += 1;
+        let h = format!("{}{}", HANDLE_PREFIX, self.next_handle);
+        if f.name.is_empty() {
+            f.name = h.clone();
+        } else {
+            self.named.insert(f.name.clone(), h.clone());
+        }
+        self.handles.insert(h.clone(), f);
+        self.pin(&h);
+        h
+    }
+
+    // keeps the handle h from being collected until the statement being run is
+    // done, since it may not have made it into a var yet
+    fn pin(&mut self, h: &str) {
+        if let Some(p) = self.pins.last_mut() {
+            p.push(h.to_string());
+        }
+    }
+
+    /// Starts a statement, for the handles made while it runs to be pinned to.
+    pub fn pin_start(&mut self) {
+        self.pins.push(Vec::new());
+    }
+
+    /// Ends the statements started since there were depth of them.  If keep,
+    /// their handles stay pinned to the statement around them, as they may be
+    /// in the output being captured for it.
+    pub fn pin_end(&mut self, depth: usize, keep: bool) {
+        while self.pins.len() > depth {
+            let p = self.pins.pop().unwrap();
+            if keep {
+                if let Some(outer) = self.pins.last_mut() {
+                    outer.extend(p);
+                }
+            }
+        }
+    }
+
+    pub fn pin_depth(&self) -> usize {
+        self.pins.len()
+    }
+
+    /// Whether enough handles and closed-over scopes have piled up since the last
+    /// collect() for another to be worth it.
+    pub fn should_collect(&self) -> bool {
+        self.handles.len() + self.closed.len() >= self.collect_at
+    }
+
+    /// Frees the fn handles which nothing refers to any more, and empties the
+    /// scopes fns closed over which can no longer be reached, which may be
+    /// keeping themselves alive through the fns set in them.  held are values
+    /// kept outside the symtable, which may name handles too.
+    pub fn collect(&mut self, held: &[String]) {
+        let mut strs = held.to_vec();
+        strs.extend(self.scopes.iter().filter_map(|sc| sc.ret_val.clone()));
+        strs.extend(self.pins.iter().flat_map(|p| p.iter().cloned()));
+        let mut envs = self.scopes.iter().map(|sc| sc.vars.clone()).collect::<Vec<_>>();
+        for m in self.modules.values() {
+            for v in m.values() {
+                match *v {
+                    Val::Var(ref s) => strs.push(s.clone()),
+                    Val::Fn(ref f) => envs.extend(f.env.0.iter().cloned()),
+                }
+            }
+        }
+
+        let mut live = HashSet::new();
+        let mut seen = HashSet::new();
+        loop {
+            if let Some(s) = strs.pop() {
+                for w in s.split_whitespace().filter(|w| w.starts_with(HANDLE_PREFIX)) {
+                    if let Some(f) = self.handles.get(w) {
+                        if live.insert(w.to_string()) {
+                            envs.extend(f.env.0.iter().cloned());
+                        }
+                    }
+                }
+            } else if let Some(vars) = envs.pop() {
+                let vars = vars.borrow();
+                if seen.insert(&*vars as *const _ as usize) {
+                    for v in vars.values() {
+                        match *v {
+                            Val::Var(ref s) => strs.push(s.clone()),
+                            Val::Fn(ref f) => envs.extend(f.env.0.iter().cloned()),
+                        }
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+
+        self.handles.retain(|h, _| live.contains(h));
+        self.named.retain(|_, h| live.contains(h));
+
+        // nothing can see into these any more, so emptying them is safe, and
+        // breaks any cycles between them and the fns they hold
+        let dead = self.closed
+            .iter()
+            .filter(|&(k, _)| !seen.contains(k))
+            .filter_map(|(_, w)| w.upgrade())
+            .collect::<Vec<_>>();
+        for vars in &dead {
+            let vals = mem::take(&mut *vars.borrow_mut());
+            drop(vals);
+        }
+        drop(dead);
+        self.closed.retain(|_, w| w.upgrade().is_some());
+
+        self.collect_at = cmp::max(COLLECT_MIN, 2 * (self.handles.len() + self.closed.len()));
+    }
+
     pub fn del_scope(&mut self) -> &mut Symtable {
         // error handling re: a bogus '}' is elsewhere
         self.scopes.pop();
@@ -254,11 +646,46 @@ impl Symtable {
         self
     }
 
+    fn path_dirs() -> Vec<String> {
+        env::var("PATH")
+            .unwrap_or("/bin:/usr/bin".to_string())
+            .split(":")
+            .filter(|d| !d.is_empty())
+            .map(|d| d.to_string())
+            .collect()
+    }
+
+    fn is_exec(p: &path::Path) -> bool {
+        match fs::metadata(p) {
+            Ok(m) => m.is_file() && m.permissions().mode() & 0o111 != 0,
+            Err(_) => false,
+        }
+    }
+
+    fn exec_path(sym_n: &str) -> Option<path::PathBuf> {
+        if Symtable::is_exec(path::Path::new(sym_n)) {
+            Some(path::PathBuf::from(sym_n))
+        } else {
+            None
+        }
+    }
+
+    // looks through PATH for just the one binary, without hashing everything
+    fn search_path(sym_n: &str) -> Option<path::PathBuf> {
+        for path_dir in Symtable::path_dirs() {
+            let p = path::Path::new(&path_dir).join(sym_n);
+            if Symtable::is_exec(&p) {
+                return Some(p);
+            }
+        }
+        None
+    }
+
     fn hash_bins(&mut self) -> &mut Symtable {
         self.bins.clear();
+        self.used.clear();
 
-        let path_str = env::var("PATH").unwrap_or("/bin:/usr/bin".to_string());
-        for path_dir in path_str.split(":") {
+        for path_dir in Symtable::path_dirs() {
             if let Ok(path_dir) = fs::read_dir(path_dir) {
                 for path_f in path_dir {
                     if let Err(e) = path_f {
@@ -266,15 +693,11 @@ impl Symtable {
                         break;
                     }
                     let path_f = path_f.unwrap();
-                    let is_ok_f = match path_f.metadata() {
-                        Ok(ent) => !ent.is_dir(),   // FIXME: should only be executable files
-                        Err(_) => false,
-                    };
-
-                    if is_ok_f {
+                    if Symtable::is_exec(&path_f.path()) {
                         if let Some(os_fname) = path_f.path().file_name() {
                             if let Ok(fname) = os_fname.to_os_string().into_string() {
-                                self.bins.insert(fname, path_f.path());
+                                // earlier dirs in PATH take precedence
+                                self.bins.entry(fname).or_insert(path_f.path());
                             }
                         }
                     }
@@ -297,13 +720,25 @@ impl Symtable {
 
         // fns
         for scope in self.scopes.iter().rev() {
-            for v in scope.vars.iter().filter(|&(x, _)| x.starts_with(sym_n)) {
+            for v in scope.vars.borrow().iter().filter(|&(x, _)| x.starts_with(sym_n)) {
                 if let (n, &Val::Fn(_)) = v {
                     res.push(n.to_owned());
                 }
             }
         }
 
+        // module fns, namespaced
+        for (m, vars) in self.modules.iter() {
+            for (n, v) in vars.iter() {
+                if let &Val::Fn(_) = v {
+                    let full = format!("{}.{}", m, n);
+                    if full.starts_with(sym_n) {
+                        res.push(full);
+                    }
+                }
+            }
+        }
+
         // builtins
         for v in self.builtins.iter().filter(|&(x, _)| x.starts_with(sym_n)) {
             res.push(v.1.name.to_string());
@@ -333,24 +768,34 @@ impl Symtable {
             }
         }
 
-        // check for Var symbol
-        for scope in self.scopes.iter().rev() {
-            if let Some(&Val::Var(ref v)) = scope.vars.get(sym_n) {
-                return Some(v.clone());
-            }
+        self.lookup(sym_n, |v| match *v {
+            Val::Var(ref v) => Some(v.clone()),
+            _ => None,
+        })
+    }
+
+    pub fn resolve_fn(&self, sym_n: &str) -> Option<Fn> {
+        if let Some(f) = self.resolve_fn_by_name(sym_n) {
+            return Some(f);
         }
 
-        None
+        // a var holding a fn's handle can be called like the fn
+        match self.resolve_var(sym_n) {
+            Some(ref v) if v.starts_with(HANDLE_PREFIX) => self.handles.get(v).cloned(),
+            _ => None,
+        }
     }
 
-    pub fn resolve_fn(&self, sym_n: &str) -> Option<Fn> {
-        for scope in self.scopes.iter().rev() {
-            if let Some(&Val::Fn(ref f)) = scope.vars.get(sym_n) {
-                return Some(f.clone());
-            }
+    fn resolve_fn_by_name(&self, sym_n: &str) -> Option<Fn> {
+        let f = self.lookup(sym_n, |v| match *v {
+            Val::Fn(ref f) => Some((**f).clone()),
+            _ => None,
+        });
+        if f.is_some() {
+            return f;
         }
 
-        None
+        self.handles.get(sym_n).cloned()
     }
 
     pub fn resolve_env(&self, sym_n: &str) -> Option<String> {
@@ -368,28 +813,58 @@ impl Symtable {
     }
 
     pub fn resolve_binary(&mut self, sym_n: &str) -> Option<path::PathBuf> {
-        // check for Binary symbol by filename
-        if let Some(bin_path) = self.bins.get(sym_n) {
-            return Some(bin_path.clone());
+        // names with a slash are paths, not looked up in PATH
+        if sym_n.contains('/') {
+            return Symtable::exec_path(sym_n);
         }
 
-        // Check for executable file by full path
-        if let Ok(_) = fs::metadata(sym_n) {
-            // FIXME: needs more sanity checking for good files
-            return Some(path::PathBuf::from(sym_n));
+        // check for Binary symbol by filename, making sure it's still there
+        if let Some(bin_path) = self.bins.get(sym_n).cloned() {
+            if Symtable::is_exec(&bin_path) {
+                self.used.insert(sym_n.to_string());
+                return Some(bin_path);
+            }
+            self.bins.remove(sym_n);
+            self.used.remove(sym_n);
         }
 
-        // Re-hash bins and check again
-        // TODO: __tin_rehash option
-        if !self.subsh {
-            if let Some(bin_path) = self.hash_bins().bins.get(sym_n) {
-                return Some(bin_path.clone());
-            }
+        // not hashed (or gone stale), so look for it alone
+        if let Some(bin_path) = Symtable::search_path(sym_n) {
+            self.bins.insert(sym_n.to_string(), bin_path.clone());
+            self.used.insert(sym_n.to_string());
+            return Some(bin_path);
         }
 
         None
     }
 
+    /// Forgets every hashed binary and hashes PATH again.
+    pub fn rehash(&mut self) {
+        self.hash_bins();
+    }
+
+    /// Returns the binaries which have been looked up or added, by name.
+    pub fn hashed(&self) -> Vec<(String, path::PathBuf)> {
+        let mut res = self.used
+            .iter()
+            .filter_map(|n| self.bins.get(n).map(|p| (n.clone(), p.clone())))
+            .collect::<Vec<_>>();
+        res.sort();
+        res
+    }
+
+    /// Remembers path as the binary for name, whatever PATH says.
+    pub fn hash_add(&mut self, name: &str, path: path::PathBuf) {
+        self.bins.insert(name.to_string(), path);
+        self.used.insert(name.to_string());
+    }
+
+    /// Forgets the hashed binary for name, returning whether there was one.
+    pub fn hash_forget(&mut self, name: &str) -> bool {
+        self.used.remove(name);
+        self.bins.remove(name).is_some()
+    }
+
     pub fn resolve_varish(&self, sym_n: &str) -> Option<SymV> {
         if let Some(res) = self.resolve_var(sym_n) {
             Some(SymV::Var(res))
@@ -402,7 +877,7 @@ impl Symtable {
 
     pub fn resolve_exec(&mut self, sym_n: &str) -> Option<SymE> {
         if let Some(res) = self.resolve_fn(sym_n) {
-            Some(SymE::Fn(res))
+            Some(SymE::Fn(Box::new(res)))
         } else if let Some(res) = self.resolve_builtin(sym_n) {
             Some(SymE::Builtin(res))
         } else if let Some(res) = self.resolve_binary(sym_n) {
@@ -413,18 +888,27 @@ impl Symtable {
     }
 
     pub fn resolve_any(&mut self, sym_n: &str) -> Option<Sym> {
-        if let Some(res) = self.resolve_var(sym_n) {
-            Some(Sym::Var(res))
-        } else if let Some(res) = self.resolve_fn(sym_n) {
-            Some(Sym::Fn(res))
-        } else if let Some(res) = self.resolve_env(sym_n) {
-            Some(Sym::Environment(res))
-        } else if let Some(res) = self.resolve_builtin(sym_n) {
-            Some(Sym::Builtin(res))
-        } else if let Some(res) = self.resolve_binary(sym_n) {
-            Some(Sym::Binary(res))
-        } else {
-            None
+        self.resolve_all(sym_n).into_iter().next()
+    }
+
+    /// Resolves every symbol sym_n could refer to, in the order they are resolved.
+    pub fn resolve_all(&mut self, sym_n: &str) -> Vec<Sym> {
+        let mut res = Vec::new();
+        if let Some(v) = self.resolve_var(sym_n) {
+            res.push(Sym::Var(v));
         }
+        if let Some(f) = self.resolve_fn(sym_n) {
+            res.push(Sym::Fn(Box::new(f)));
+        }
+        if let Some(e) = self.resolve_env(sym_n) {
+            res.push(Sym::Environment(e));
+        }
+        if let Some(b) = self.resolve_builtin(sym_n) {
+            res.push(Sym::Builtin(b));
+        }
+        if let Some(p) = self.resolve_binary(sym_n) {
+            res.push(Sym::Binary(p));
+        }
+        res
     }
 }
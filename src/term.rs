@@ -0,0 +1,607 @@
+use std::cmp;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+extern crate unicode_segmentation;
+use self::unicode_segmentation::UnicodeSegmentation;
+
+use editor;
+
+// where compiled terminfo entries are looked for, after $TERMINFO,
+// ~/.terminfo and $TERMINFO_DIRS
+const TERMINFO_DIRS: [&str; 4] = ["/etc/terminfo",
+                                          "/lib/terminfo",
+                                          "/usr/share/terminfo",
+                                          "/usr/lib/terminfo"];
+
+// the capabilities we use, by their place in terminfo's lists
+const AUTO_MARGINS: usize = 1; // am
+const EAT_NEWLINE: usize = 4; // xenl
+const COLUMNS: usize = 0; // cols
+const BELL: usize = 1; // bel
+const CARRIAGE_RETURN: usize = 2; // cr
+const CLEAR_SCREEN: usize = 5; // clear
+const CLR_EOL: usize = 6; // el
+const CLR_EOS: usize = 7; // ed
+const COLUMN_ADDRESS: usize = 8; // hpa
+const PARM_DOWN_CURSOR: usize = 107; // cud
+const PARM_UP_CURSOR: usize = 114; // cuu
+
+/// Caps: how to get the terminal to do the few things the prompt needs of it.
+pub struct Caps {
+    pub bell: String,
+    pub cr: String,
+    pub clear: String,
+    pub clr_eol: String,
+    pub clr_eos: String,
+    // these take a parameter, so are kept as terminfo has them
+    column: String,
+    up: String,
+    down: String,
+    // a row filled up leaves the cursor at its end, rather than on the next
+    pub eat_newline: bool,
+    pub cols: Option<usize>,
+}
+
+impl Caps {
+    /// The ANSI sequences, which any terminal we're likely to meet knows.
+    pub fn ansi() -> Self {
+        Caps {
+            bell: "\x07".to_string(),
+            cr: "\r".to_string(),
+            clear: "\x1b[H\x1b[2J".to_string(),
+            clr_eol: "\x1b[K".to_string(),
+            clr_eos: "\x1b[J".to_string(),
+            column: "\x1b[%i%p1%dG".to_string(),
+            up: "\x1b[%p1%dA".to_string(),
+            down: "\x1b[%p1%dB".to_string(),
+            eat_newline: true,
+            cols: None,
+        }
+    }
+
+    /// The capabilities of the terminal $TERM names, from its terminfo entry.
+    /// Whatever the entry doesn't have (or we can't expand) is left as in
+    /// ansi(), as is everything if there's no entry to be found.
+    pub fn load() -> Self {
+        let mut caps = Caps::ansi();
+        let ti = match env::var("TERM").ok().and_then(|t| read_terminfo(&t)) {
+            Some(ti) => ti,
+            None => return caps,
+        };
+
+        {
+            let plain = |cap: &mut String, i: usize| {
+                if let Some(s) = ti.string(i).and_then(|s| tparm(s, &[])) {
+                    *cap = s;
+                }
+            };
+            plain(&mut caps.bell, BELL);
+            plain(&mut caps.cr, CARRIAGE_RETURN);
+            plain(&mut caps.clear, CLEAR_SCREEN);
+            plain(&mut caps.clr_eol, CLR_EOL);
+            plain(&mut caps.clr_eos, CLR_EOS);
+        }
+        {
+            let parm = |cap: &mut String, i: usize| {
+                if let Some(s) = ti.string(i) {
+                    if tparm(s, &[1]).is_some() {
+                        *cap = s.to_string();
+                    }
+                }
+            };
+            parm(&mut caps.column, COLUMN_ADDRESS);
+            parm(&mut caps.up, PARM_UP_CURSOR);
+            parm(&mut caps.down, PARM_DOWN_CURSOR);
+        }
+        caps.eat_newline = !ti.flag(AUTO_MARGINS) || ti.flag(EAT_NEWLINE);
+        caps.cols = ti.number(COLUMNS);
+        caps
+    }
+
+    pub fn up(&self, n: usize) -> String {
+        if n == 0 {
+            return String::new();
+        }
+        tparm(&self.up, &[n]).unwrap_or_default()
+    }
+
+    pub fn down(&self, n: usize) -> String {
+        if n == 0 {
+            return String::new();
+        }
+        tparm(&self.down, &[n]).unwrap_or_default()
+    }
+
+    /// Moves to column col of the row, counting from 0.
+    pub fn column(&self, col: usize) -> String {
+        tparm(&self.column, &[col]).unwrap_or_default()
+    }
+}
+
+// a compiled terminfo entry; only the parts we've a use for are kept
+struct Terminfo {
+    flags: Vec<bool>,
+    numbers: Vec<Option<usize>>,
+    strings: Vec<Option<String>>,
+}
+
+impl Terminfo {
+    fn flag(&self, i: usize) -> bool {
+        self.flags.get(i).cloned().unwrap_or(false)
+    }
+
+    fn number(&self, i: usize) -> Option<usize> {
+        self.numbers.get(i).cloned().unwrap_or(None)
+    }
+
+    fn string(&self, i: usize) -> Option<&str> {
+        match self.strings.get(i) {
+            Some(Some(s)) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+fn read_terminfo(term: &str) -> Option<Terminfo> {
+    let first = match term.chars().next() {
+        Some(c) if !term.contains('/') => c,
+        _ => return None,
+    };
+
+    let mut dirs = Vec::new();
+    if let Ok(d) = env::var("TERMINFO") {
+        dirs.push(PathBuf::from(d));
+    }
+    if let Ok(h) = env::var("HOME") {
+        dirs.push(PathBuf::from(h).join(".terminfo"));
+    }
+    if let Ok(ds) = env::var("TERMINFO_DIRS") {
+        for d in ds.split(':') {
+            // an empty entry stands for the usual places
+            if d.is_empty() {
+                dirs.extend(TERMINFO_DIRS.iter().map(PathBuf::from));
+            } else {
+                dirs.push(PathBuf::from(d));
+            }
+        }
+    }
+    dirs.extend(TERMINFO_DIRS.iter().map(PathBuf::from));
+
+    for dir in dirs {
+        // entries go by their first letter, or on some systems its hex code
+        for sub in &[first.to_string(), format!("{:x}", first as u32)] {
+            let mut data = Vec::new();
+            let read = File::open(dir.join(sub).join(term)).and_then(|mut f| f.read_to_end(&mut data));
+            if read.is_ok() {
+                return parse_terminfo(&data);
+            }
+        }
+    }
+    None
+}
+
+fn parse_terminfo(data: &[u8]) -> Option<Terminfo> {
+    let word = |at: usize| -> Option<i32> {
+        if at + 1 < data.len() {
+            Some(((data[at] as u16) | (data[at + 1] as u16) << 8) as i16 as i32)
+        } else {
+            None
+        }
+    };
+    let size = |at: usize| word(at).and_then(|n| if n < 0 { None } else { Some(n as usize) });
+
+    // newer entries have room for bigger numbers
+    let num_len = match word(0) {
+        Some(0o432) => 2,
+        Some(0o1036) => 4,
+        _ => return None,
+    };
+    let (names, n_flags, n_nums, n_strs, table_len) = (try_opt!(size(2)),
+                                                     try_opt!(size(4)),
+                                                     try_opt!(size(6)),
+                                                     try_opt!(size(8)),
+                                                     try_opt!(size(10)));
+
+    let mut at = 12 + names;
+    let flags = try_opt!(data.get(at..at + n_flags)).iter().map(|&b| b == 1).collect();
+    at += n_flags;
+    // the numbers start on an even byte
+    at += at % 2;
+
+    let mut numbers = Vec::new();
+    for i in 0..n_nums {
+        let n = if num_len == 2 {
+            try_opt!(word(at + 2 * i))
+        } else {
+            let b = try_opt!(data.get(at + 4 * i..at + 4 * i + 4));
+            (b[0] as i32) | (b[1] as i32) << 8 | (b[2] as i32) << 16 | (b[3] as i32) << 24
+        };
+        numbers.push(if n < 0 { None } else { Some(n as usize) });
+    }
+    at += num_len * n_nums;
+
+    let table = try_opt!(data.get(at + 2 * n_strs..at + 2 * n_strs + table_len));
+    let mut strings = Vec::new();
+    for i in 0..n_strs {
+        let s = match try_opt!(word(at + 2 * i)) {
+            off if off < 0 => None,
+            off => {
+                let s = try_opt!(table.get(off as usize..));
+                let end = s.iter().position(|&b| b == 0).unwrap_or(s.len());
+                Some(String::from_utf8_lossy(&s[..end]).into_owned())
+            }
+        };
+        strings.push(s);
+    }
+
+    Some(Terminfo {
+        flags,
+        numbers,
+        strings,
+    })
+}
+
+// expands a terminfo string with its parameters.  only as much of the
+// language as cursor motion needs is understood; anything past that is None
+fn tparm(cap: &str, params: &[usize]) -> Option<String> {
+    let mut params = params.iter().map(|&p| p as i64).collect::<Vec<_>>();
+    let mut stack: Vec<i64> = Vec::new();
+    let mut out = String::new();
+    let mut chars = cap.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '$' && chars.peek() == Some(&'<') {
+            // padding, for terminals slower than any we'll see
+            for c in chars.by_ref() {
+                if c == '>' {
+                    break;
+                }
+            }
+            continue;
+        }
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => out.push('%'),
+            Some('i') => {
+                for p in params.iter_mut().take(2) {
+                    *p += 1;
+                }
+            }
+            Some('p') => {
+                match chars.next().and_then(|d| d.to_digit(10)) {
+                    Some(n) if n > 0 => stack.push(params.get(n as usize - 1).cloned().unwrap_or(0)),
+                    _ => return None,
+                }
+            }
+            Some('d') => out.push_str(&stack.pop().unwrap_or(0).to_string()),
+            Some('c') => out.push(stack.pop().unwrap_or(0) as u8 as char),
+            Some('{') => {
+                let mut n = 0;
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(d) if d.is_ascii_digit() => n = n * 10 + d.to_digit(10).unwrap() as i64,
+                        _ => return None,
+                    }
+                }
+                stack.push(n);
+            }
+            Some(op @ '+') | Some(op @ '-') => {
+                let b = stack.pop().unwrap_or(0);
+                let a = stack.pop().unwrap_or(0);
+                stack.push(if op == '+' { a + b } else { a - b });
+            }
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+// the length of the escape sequence s starts with, if it does.  these take
+// up no room on the screen
+fn escape_len(s: &str) -> usize {
+    let b = s.as_bytes();
+    if b.first() != Some(&0x1b) {
+        return 0;
+    }
+    match b.get(1) {
+        // CSI: parameters, then a final byte
+        Some(&b'[') => {
+            b.iter().skip(2).position(|&c| (0x40..=0x7e).contains(&c)).map(|i| i + 3).unwrap_or(b.len())
+        }
+        // OSC, as for a window title: up to BEL or ESC \
+        Some(&b']') => {
+            let mut i = 2;
+            while i < b.len() {
+                if b[i] == 0x07 {
+                    return i + 1;
+                } else if b[i] == 0x1b && b.get(i + 1) == Some(&b'\\') {
+                    return i + 2;
+                }
+                i += 1;
+            }
+            b.len()
+        }
+        Some(&c) if c < 0x80 => 2,
+        _ => 1,
+    }
+}
+
+/// Term: what we know of the terminal's screen, so that the cursor can be
+/// put where it's wanted without asking the terminal where it is.
+pub struct Term {
+    pub caps: Caps,
+    pub cols: usize,
+    pub row: usize, // the row the cursor's on, counting from the one drawing started on
+}
+
+impl Term {
+    pub fn new() -> Self {
+        Term {
+            caps: Caps::load(),
+            cols: 80,
+            row: 0,
+        }
+    }
+
+    /// Takes the terminal's width, as the system has it, if it knows.
+    pub fn set_cols(&mut self, cols: Option<usize>) {
+        self.cols = match cols {
+            Some(c) if c > 0 => c,
+            _ => self.caps.cols.unwrap_or(80),
+        };
+    }
+
+    /// Lays out text drawn from column start (counting from 0), giving the
+    /// row and column the cursor ends at and what to print to draw it.  Rows
+    /// which fill up wrap onto the next, and lines after the first line up
+    /// with start.
+    pub fn layout(&self, start: usize, text: &str) -> (usize, usize, String) {
+        let cols = cmp::max(self.cols, 1);
+        let indent = if start >= cols { 0 } else { start };
+        let mut row = 0;
+        let mut col = cmp::min(start, cols);
+        let mut out = String::new();
+        let mut rest = text;
+        while !rest.is_empty() {
+            let esc = escape_len(rest);
+            if esc > 0 {
+                out.push_str(&rest[..esc]);
+                rest = &rest[esc..];
+                continue;
+            }
+            let g = rest.graphemes(true).next().unwrap_or(rest);
+            rest = &rest[g.len()..];
+
+            let w = editor::width(g);
+            if g == "\n" || col + w > cols {
+                out.push_str(&self.next_row(col));
+                row += 1;
+                col = if g == "\n" { indent } else { 0 };
+                if col > 0 {
+                    out.push_str(&self.caps.column(col));
+                }
+                if g == "\n" {
+                    continue;
+                }
+            }
+            out.push_str(g);
+            col += w;
+        }
+        // leave the cursor on the next row, not at the end of a full one
+        if col >= cols {
+            out.push_str(&self.next_row(col));
+            row += 1;
+            col = 0;
+        }
+        (row, col, out)
+    }
+
+    // to the start of the next row, from column col of this one
+    fn next_row(&self, col: usize) -> String {
+        if col >= self.cols && !self.caps.eat_newline {
+            // the terminal's already gone on to it
+            String::new()
+        } else {
+            format!("{}\n", self.caps.cr)
+        }
+    }
+
+    /// Moves the cursor to the given row and column.
+    pub fn move_to(&mut self, row: usize, col: usize) {
+        if row < self.row {
+            print!("{}", self.caps.up(self.row - row));
+        } else if row > self.row {
+            print!("{}", self.caps.down(row - self.row));
+        }
+        print!("{}", self.caps.column(col));
+        self.row = row;
+    }
+
+    /// Draws text from column start of the first row on, clearing what was
+    /// there, with the cursor left at byte cursor of it.
+    pub fn draw(&mut self, start: usize, text: &str, cursor: usize) {
+        self.move_to(0, start);
+        let (end, _, out) = self.layout(start, text);
+        print!("{}{}", self.caps.clr_eos, out);
+        self.row = end;
+        let (row, col, _) = self.layout(start, &text[..cursor]);
+        self.move_to(row, col);
+    }
+
+    pub fn bell(&self) {
+        print!("{}", self.caps.bell);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use super::*;
+
+    // as /lib/terminfo/x/xterm-256color has it, in the newer format with
+    // 32-bit numbers
+    const XTERM_256COLOR: &[u8] = include_bytes!("testdata/xterm-256color");
+
+    fn le16(out: &mut Vec<u8>, n: i16) {
+        out.push(n as u8);
+        out.push((n >> 8) as u8);
+    }
+
+    // a small entry in the older format, with 16-bit numbers: am, cols#132
+    // and bel, with el missing
+    fn legacy_entry() -> Vec<u8> {
+        let names = b"tiny|a tiny terminal\0";
+        let table = b"\x07\0";
+        let mut out = Vec::new();
+        for &n in &[0o432, names.len() as i16, 2, 1, 7, table.len() as i16] {
+            le16(&mut out, n);
+        }
+        out.extend_from_slice(names);
+        out.extend_from_slice(&[0, 1]);
+        if out.len() % 2 == 1 {
+            out.push(0);
+        }
+        le16(&mut out, 132);
+        for &off in &[-1, 0, -1, -1, -1, -1, -1] {
+            le16(&mut out, off);
+        }
+        out.extend_from_slice(table);
+        out
+    }
+
+    fn term(cols: usize, eat_newline: bool) -> Term {
+        let mut caps = Caps::ansi();
+        caps.eat_newline = eat_newline;
+        Term {
+            caps,
+            cols,
+            row: 0,
+        }
+    }
+
+    #[test]
+    fn parses_xterm_256color() {
+        let ti = parse_terminfo(XTERM_256COLOR).unwrap();
+        assert!(ti.flag(AUTO_MARGINS));
+        assert!(ti.flag(EAT_NEWLINE));
+        assert_eq!(ti.number(COLUMNS), Some(80));
+        assert_eq!(ti.string(BELL), Some("\x07"));
+        assert_eq!(ti.string(CARRIAGE_RETURN), Some("\r"));
+        assert_eq!(ti.string(CLEAR_SCREEN), Some("\x1b[H\x1b[2J"));
+        assert_eq!(ti.string(CLR_EOL), Some("\x1b[K"));
+        assert_eq!(ti.string(CLR_EOS), Some("\x1b[J"));
+        assert_eq!(ti.string(COLUMN_ADDRESS), Some("\x1b[%i%p1%dG"));
+        assert_eq!(ti.string(PARM_UP_CURSOR), Some("\x1b[%p1%dA"));
+        assert_eq!(ti.string(PARM_DOWN_CURSOR), Some("\x1b[%p1%dB"));
+    }
+
+    #[test]
+    fn parses_legacy_entry() {
+        let ti = parse_terminfo(&legacy_entry()).unwrap();
+        assert!(ti.flag(AUTO_MARGINS));
+        assert!(!ti.flag(EAT_NEWLINE));
+        assert_eq!(ti.number(COLUMNS), Some(132));
+        assert_eq!(ti.string(BELL), Some("\x07"));
+        assert_eq!(ti.string(CLR_EOL), None);
+        assert_eq!(ti.string(PARM_UP_CURSOR), None);
+    }
+
+    #[test]
+    fn rejects_mangled_entries() {
+        assert!(parse_terminfo(b"").is_none());
+        assert!(parse_terminfo(b"\x1a\x01").is_none());
+        assert!(parse_terminfo(&XTERM_256COLOR[..XTERM_256COLOR.len() / 2]).is_none());
+        let mut bad = XTERM_256COLOR.to_vec();
+        bad[0] = 0;
+        assert!(parse_terminfo(&bad).is_none());
+    }
+
+    #[test]
+    fn falls_back_without_terminfo() {
+        env::set_var("TERM", "tin-no-such-terminal");
+        let caps = Caps::load();
+        let ansi = Caps::ansi();
+        assert_eq!(caps.bell, ansi.bell);
+        assert_eq!(caps.cr, ansi.cr);
+        assert_eq!(caps.clear, ansi.clear);
+        assert_eq!(caps.clr_eol, ansi.clr_eol);
+        assert_eq!(caps.clr_eos, ansi.clr_eos);
+        assert_eq!(caps.column(0), "\x1b[1G");
+        assert_eq!(caps.up(2), "\x1b[2A");
+        assert_eq!(caps.down(3), "\x1b[3B");
+        assert!(caps.eat_newline);
+        assert_eq!(caps.cols, None);
+    }
+
+    #[test]
+    fn expands_motion_caps() {
+        let ti = parse_terminfo(XTERM_256COLOR).unwrap();
+        let cap = |i| ti.string(i).unwrap();
+        // hpa counts columns from 1
+        assert_eq!(tparm(cap(COLUMN_ADDRESS), &[0]), Some("\x1b[1G".to_string()));
+        assert_eq!(tparm(cap(COLUMN_ADDRESS), &[79]), Some("\x1b[80G".to_string()));
+        assert_eq!(tparm(cap(PARM_UP_CURSOR), &[1]), Some("\x1b[1A".to_string()));
+        assert_eq!(tparm(cap(PARM_DOWN_CURSOR), &[12]), Some("\x1b[12B".to_string()));
+    }
+
+    #[test]
+    fn tparm_odd_bits() {
+        assert_eq!(tparm("a$<5*/>b%%", &[]), Some("ab%".to_string()));
+        assert_eq!(tparm("%p1%{32}%+%c", &[1]), Some("!".to_string()));
+        // conditionals aren't understood
+        assert_eq!(tparm("%?%p1%t;%;", &[1]), None);
+    }
+
+    #[test]
+    fn layout_exact_width() {
+        // the cursor has to be taken to the next row by hand
+        assert_eq!(term(10, true).layout(0, "abcdefghij"),
+                   (1, 0, "abcdefghij\r\n".to_string()));
+        // the terminal's already put it there
+        assert_eq!(term(10, false).layout(0, "abcdefghij"),
+                   (1, 0, "abcdefghij".to_string()));
+        assert_eq!(term(10, true).layout(4, "efghij"),
+                   (1, 0, "efghij\r\n".to_string()));
+    }
+
+    #[test]
+    fn layout_past_width() {
+        assert_eq!(term(10, true).layout(0, "abcdefghijk"),
+                   (1, 1, "abcdefghij\r\nk".to_string()));
+        assert_eq!(term(10, false).layout(0, "abcdefghijk"),
+                   (1, 1, "abcdefghijk".to_string()));
+        assert_eq!(term(10, true).layout(0, "abcdefghi"),
+                   (0, 9, "abcdefghi".to_string()));
+    }
+
+    #[test]
+    fn layout_wide_char_at_edge() {
+        // a wide char can't be split, so it goes whole onto the next row and
+        // the last column is left empty, xenl or not
+        for &xenl in &[true, false] {
+            assert_eq!(term(10, xenl).layout(0, "abcdefghi\u{5b57}"),
+                       (1, 2, "abcdefghi\r\n\u{5b57}".to_string()));
+            assert_eq!(term(10, xenl).layout(0, "abcdefgh\u{5b57}"),
+                       (1, 0, if xenl { "abcdefgh\u{5b57}\r\n" } else { "abcdefgh\u{5b57}" }.to_string()));
+        }
+    }
+
+    #[test]
+    fn layout_newlines_and_escapes() {
+        // lines after the first line up with where the first started
+        assert_eq!(term(10, true).layout(2, "ab\ncd"),
+                   (1, 4, "ab\r\n\x1b[3Gcd".to_string()));
+        // escapes take up no room
+        assert_eq!(term(4, true).layout(0, "\x1b[1mabc\x1b[0m"),
+                   (0, 3, "\x1b[1mabc\x1b[0m".to_string()));
+        assert_eq!(escape_len("\x1b]0;title\x07rest"), 10);
+        assert_eq!(escape_len("\x1b]0;t\x1b\\rest"), 7);
+        assert_eq!(escape_len("plain"), 0);
+    }
+}
@@ -0,0 +1,566 @@
+use std::cmp;
+
+use editor::EditCmd;
+use editor::Editor;
+
+/// What the prompt should do after a key in vi's normal mode.
+pub enum ViAction {
+    Done, // the line may have changed
+    Pending, // the command isn't complete yet
+    Mode, // we've gone into insert mode
+    Run(EditCmd), // the prompt has to run this for us
+    Bad,
+}
+
+// a normal mode command: [count] [op [count]] key [arg]
+struct ViCmd {
+    count: Option<usize>,
+    op: Option<char>,
+    key: char,
+    arg: Option<char>,
+}
+
+enum Parse {
+    Incomplete,
+    Bad,
+    Cmd(ViCmd),
+}
+
+// counts past this are clamped, so a stray run of digits can't make a
+// motion or a paste spin for ages
+const MAX_COUNT: usize = 9999;
+
+fn count<I: Iterator<Item = char>>(cs: &mut ::std::iter::Peekable<I>) -> Option<usize> {
+    let mut n: Option<usize> = None;
+    while let Some(&c) = cs.peek() {
+        // a '0' to start with is the motion, not a count
+        match c.to_digit(10) {
+            Some(d) if d > 0 || n.is_some() => {
+                let m = n.unwrap_or(0).saturating_mul(10).saturating_add(d as usize);
+                n = Some(cmp::min(m, MAX_COUNT));
+                cs.next();
+            }
+            _ => break,
+        }
+    }
+    n
+}
+
+fn parse(keys: &str) -> Parse {
+    let mut cs = keys.chars().peekable();
+    let c1 = count(&mut cs);
+    let key = match cs.next() {
+        Some(k) => k,
+        None => return Parse::Incomplete,
+    };
+    let (op, c2, key) = if "dcy".contains(key) {
+        let c2 = count(&mut cs);
+        match cs.next() {
+            Some(m) => (Some(key), c2, m),
+            None => return Parse::Incomplete,
+        }
+    } else {
+        (None, None, key)
+    };
+    let arg = if "fFtTr".contains(key) {
+        match cs.next() {
+            Some(a) => Some(a),
+            None => return Parse::Incomplete,
+        }
+    } else {
+        None
+    };
+    if cs.next().is_some() {
+        return Parse::Bad;
+    }
+
+    Parse::Cmd(ViCmd {
+        count: match (c1, c2) {
+            (None, None) => None,
+            (a, b) => Some(cmp::min(a.unwrap_or(1).saturating_mul(b.unwrap_or(1)), MAX_COUNT)),
+        },
+        op,
+        key,
+        arg,
+    })
+}
+
+// the kinds of chars vi's words are made of: blanks, word chars and the rest;
+// big words are anything but blanks
+fn class(c: char, big: bool) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if big || c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+
+fn class_at(ed: &Editor, i: usize, big: bool) -> u8 {
+    ed.char_at(i).map(|c| class(c, big)).unwrap_or(0)
+}
+
+fn word_fwd(ed: &Editor, i: usize, big: bool) -> usize {
+    let mut i = i;
+    let c = class_at(ed, i, big);
+    while i < ed.buf.len() && c != 0 && class_at(ed, i, big) == c {
+        i = ed.next_char(i);
+    }
+    while i < ed.buf.len() && class_at(ed, i, big) == 0 {
+        i = ed.next_char(i);
+    }
+    i
+}
+
+fn word_back(ed: &Editor, i: usize, big: bool) -> usize {
+    let mut i = ed.prev_char(i);
+    while i > 0 && class_at(ed, i, big) == 0 {
+        i = ed.prev_char(i);
+    }
+    let c = class_at(ed, i, big);
+    while i > 0 && class_at(ed, ed.prev_char(i), big) == c {
+        i = ed.prev_char(i);
+    }
+    i
+}
+
+fn word_end(ed: &Editor, i: usize, big: bool) -> usize {
+    let mut i = ed.next_char(i);
+    while i < ed.buf.len() && class_at(ed, i, big) == 0 {
+        i = ed.next_char(i);
+    }
+    let c = class_at(ed, i, big);
+    while ed.next_char(i) < ed.buf.len() && class_at(ed, ed.next_char(i), big) == c {
+        i = ed.next_char(i);
+    }
+    cmp::min(i, ed.prev_char(ed.buf.len()))
+}
+
+fn first_nonblank(ed: &Editor) -> usize {
+    ed.buf.find(|c: char| !c.is_whitespace()).unwrap_or(ed.buf.len())
+}
+
+// where key moves the cursor to, n times over, and whether an operator
+// should take in the char there too
+fn motion(ed: &Editor, key: char, arg: Option<char>, n: usize) -> Option<(usize, bool)> {
+    let mut i = ed.idx;
+    Some(match key {
+        'h' => ((0..n).fold(i, |i, _| ed.prev_char(i)), false),
+        'l' | ' ' => ((0..n).fold(i, |i, _| ed.next_char(i)), false),
+        '0' => (0, false),
+        '^' => (first_nonblank(ed), false),
+        '$' => (ed.buf.len(), false),
+        'w' | 'W' => ((0..n).fold(i, |i, _| word_fwd(ed, i, key == 'W')), false),
+        'b' | 'B' => ((0..n).fold(i, |i, _| word_back(ed, i, key == 'B')), false),
+        'e' | 'E' => ((0..n).fold(i, |i, _| word_end(ed, i, key == 'E')), true),
+        'f' | 't' | 'F' | 'T' => {
+            let fwd = key == 'f' || key == 't';
+            let c = try_opt!(arg);
+            for _ in 0..n {
+                let found = if fwd {
+                    let from = ed.next_char(i);
+                    ed.buf[from..].find(c).map(|j| from + j)
+                } else {
+                    ed.buf[..i].rfind(c)
+                };
+                match found {
+                    Some(j) => i = j,
+                    None => return None,
+                }
+            }
+            match key {
+                'f' => (i, true),
+                't' => (ed.prev_char(i), true),
+                'F' => (i, false),
+                _ => (ed.next_char(i), false),
+            }
+        }
+        _ => return None,
+    })
+}
+
+// whether the command changes the line, and so is one for '.' to repeat
+fn is_change(cmd: &ViCmd) -> bool {
+    match cmd.op {
+        Some('d') | Some('c') => true,
+        Some(_) => false,
+        None => "xXDCsSpPriaIA".contains(cmd.key),
+    }
+}
+
+/// Vi: the state of vi-style editing -- whether we're inserting or in normal
+/// mode, and what a normal mode command has been typed so far.
+pub struct Vi {
+    pub insert: bool,
+    pending: String,
+    last_change: Option<(String, String)>, // the keys, and any text inserted after
+    recording: Option<String>,
+    inserted: String,
+}
+
+impl Vi {
+    pub fn new() -> Self {
+        Vi {
+            insert: true,
+            pending: String::new(),
+            last_change: None,
+            recording: None,
+            inserted: String::new(),
+        }
+    }
+
+    /// Each line starts off in insert mode.
+    pub fn start_line(&mut self) {
+        self.insert = true;
+        self.pending.clear();
+        self.recording = None;
+    }
+
+    /// Notes text typed in insert mode, for '.' to put in again.
+    pub fn note_insert(&mut self, s: &str) {
+        self.inserted.push_str(s);
+    }
+
+    pub fn note_backspace(&mut self) {
+        self.inserted.pop();
+    }
+
+    /// Leaves insert mode, moving back onto the last char inserted.
+    pub fn enter_normal(&mut self, ed: &mut Editor) {
+        self.insert = false;
+        if let Some(keys) = self.recording.take() {
+            self.last_change = Some((keys, self.inserted.clone()));
+        }
+        ed.end_insert();
+        ed.idx = ed.prev_char(ed.idx);
+    }
+
+    fn enter_insert(&mut self) -> ViAction {
+        self.insert = true;
+        self.recording = None;
+        self.inserted.clear();
+        ViAction::Mode
+    }
+
+    /// Takes a key in normal mode.
+    pub fn normal_key(&mut self, ed: &mut Editor, key: &str) -> ViAction {
+        if key.starts_with('\x1b') {
+            // escape leaves a half-typed command
+            let r = if self.pending.is_empty() {
+                ViAction::Bad
+            } else {
+                ViAction::Done
+            };
+            self.pending.clear();
+            return r;
+        }
+
+        self.pending.push_str(key);
+        let cmd = match parse(&self.pending) {
+            Parse::Incomplete => return ViAction::Pending,
+            Parse::Bad => {
+                self.pending.clear();
+                return ViAction::Bad;
+            }
+            Parse::Cmd(cmd) => cmd,
+        };
+        let keys = ::std::mem::take(&mut self.pending);
+
+        if cmd.key == '.' && cmd.op.is_none() {
+            return self.repeat(ed);
+        }
+
+        let change = is_change(&cmd);
+        let res = self.exec(ed, cmd);
+        match res {
+            ViAction::Mode => {
+                self.recording = Some(keys);
+            }
+            ViAction::Done if change => {
+                self.last_change = Some((keys, String::new()));
+            }
+            _ => {}
+        }
+        self.clamp(ed);
+        res
+    }
+
+    /// Whether part of a command has been typed.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// In normal mode, the cursor sits on a char, not past the last one.
+    pub fn clamp(&self, ed: &mut Editor) {
+        if !self.insert && ed.idx >= ed.buf.len() {
+            ed.idx = ed.prev_char(ed.buf.len());
+        }
+    }
+
+    fn repeat(&mut self, ed: &mut Editor) -> ViAction {
+        let (keys, text) = match self.last_change.clone() {
+            Some(c) => c,
+            None => return ViAction::Bad,
+        };
+        let cmd = match parse(&keys) {
+            Parse::Cmd(cmd) => cmd,
+            _ => return ViAction::Bad,
+        };
+        if let ViAction::Mode = self.exec(ed, cmd) {
+            ed.insert(&text);
+            self.insert = false;
+            self.recording = None;
+            ed.end_insert();
+            ed.idx = ed.prev_char(ed.idx);
+        }
+        self.clamp(ed);
+        ViAction::Done
+    }
+
+    fn exec(&mut self, ed: &mut Editor, cmd: ViCmd) -> ViAction {
+        let n = cmd.count.unwrap_or(1);
+        let with_op = |op: char, key: char| {
+            ViCmd {
+                count: cmd.count,
+                op: Some(op),
+                key,
+                arg: None,
+            }
+        };
+
+        if let Some(op) = cmd.op {
+            let (from, to) = if cmd.key == op {
+                // dd, cc, yy: the whole line
+                (0, ed.buf.len())
+            } else {
+                // cw changes just to the end of the word, like ce
+                let key = match (op, cmd.key) {
+                    ('c', 'w') => 'e',
+                    ('c', 'W') => 'E',
+                    (_, k) => k,
+                };
+                match motion(ed, key, cmd.arg, n) {
+                    Some((t, incl)) => {
+                        let t = if incl { ed.next_char(t) } else { t };
+                        (cmp::min(ed.idx, t), cmp::max(ed.idx, t))
+                    }
+                    None => return ViAction::Bad,
+                }
+            };
+            return match op {
+                'y' => {
+                    ed.copy(from, to);
+                    ed.idx = from;
+                    ViAction::Done
+                }
+                'd' => {
+                    ed.snapshot();
+                    ed.cut(from, to);
+                    ViAction::Done
+                }
+                _ => {
+                    ed.snapshot();
+                    ed.cut(from, to);
+                    ed.continue_insert();
+                    self.enter_insert()
+                }
+            };
+        }
+
+        if "iaIA".contains(cmd.key) {
+            // what's typed now is undone apart from what came before
+            ed.end_insert();
+        }
+        match cmd.key {
+            'i' => self.enter_insert(),
+            'a' => {
+                ed.idx = ed.next_char(ed.idx);
+                self.enter_insert()
+            }
+            'I' => {
+                ed.idx = first_nonblank(ed);
+                self.enter_insert()
+            }
+            'A' => {
+                ed.idx = ed.buf.len();
+                self.enter_insert()
+            }
+            'x' => self.exec(ed, with_op('d', 'l')),
+            'X' => self.exec(ed, with_op('d', 'h')),
+            'D' => self.exec(ed, with_op('d', '$')),
+            'C' => self.exec(ed, with_op('c', '$')),
+            's' => self.exec(ed, with_op('c', 'l')),
+            'S' => self.exec(ed, with_op('c', 'c')),
+            'p' | 'P' => {
+                ed.snapshot();
+                ed.paste(cmd.key == 'p', n);
+                ViAction::Done
+            }
+            'u' => {
+                for _ in 0..n {
+                    ed.run(EditCmd::Undo);
+                }
+                ViAction::Done
+            }
+            'r' => {
+                let c = cmd.arg.unwrap();
+                let from = ed.idx;
+                let to = (0..n).fold(from, |i, _| ed.next_char(i));
+                if ed.char_pos(to) - ed.char_pos(from) < n {
+                    return ViAction::Bad;
+                }
+                ed.snapshot();
+                ed.buf.drain(from..to);
+                ed.buf.insert_str(from, &c.to_string().repeat(n));
+                ed.idx = ed.prev_char(from + c.len_utf8() * n);
+                ViAction::Done
+            }
+            'j' | '+' => ViAction::Run(EditCmd::NextHistory),
+            'k' | '-' => ViAction::Run(EditCmd::PreviousHistory),
+            key => {
+                match motion(ed, key, cmd.arg, n) {
+                    Some((t, _)) => {
+                        ed.idx = t;
+                        ViAction::Done
+                    }
+                    None => ViAction::Bad,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a line in normal mode, with the cursor on byte idx
+    fn normal(buf: &str, idx: usize) -> (Vi, Editor) {
+        let mut ed = Editor::new();
+        ed.buf = buf.to_string();
+        ed.idx = idx + 1;
+        let mut vi = Vi::new();
+        vi.enter_normal(&mut ed);
+        (vi, ed)
+    }
+
+    fn keys(vi: &mut Vi, ed: &mut Editor, keys: &str) -> ViAction {
+        let mut res = ViAction::Pending;
+        for c in keys.chars() {
+            res = vi.normal_key(ed, &c.to_string());
+        }
+        res
+    }
+
+    fn cmd(keys: &str) -> ViCmd {
+        match parse(keys) {
+            Parse::Cmd(c) => c,
+            _ => panic!("'{}' didn't parse", keys),
+        }
+    }
+
+    #[test]
+    fn parses() {
+        let c = cmd("2d3w");
+        assert_eq!((c.count, c.op, c.key, c.arg), (Some(6), Some('d'), 'w', None));
+        let c = cmd("fx");
+        assert_eq!((c.count, c.op, c.key, c.arg), (None, None, 'f', Some('x')));
+        // a leading 0 is a motion, not a count
+        assert_eq!(cmd("0").key, '0');
+        assert_eq!(cmd("10l").count, Some(10));
+        match parse("2d") {
+            Parse::Incomplete => {}
+            _ => panic!("'2d' should want more"),
+        }
+        match parse("xy") {
+            Parse::Bad => {}
+            _ => panic!("'xy' should be bad"),
+        }
+    }
+
+    #[test]
+    fn caps_counts() {
+        assert_eq!(cmd("99999999999999999999d99w").count, Some(MAX_COUNT));
+        assert_eq!(cmd("99999l").count, Some(MAX_COUNT));
+        assert_eq!(cmd("500d500w").count, Some(MAX_COUNT));
+
+        let (mut vi, mut ed) = normal("a b c", 0);
+        keys(&mut vi, &mut ed, "99999999999999999999d99w");
+        assert_eq!(ed.buf, "");
+    }
+
+    #[test]
+    fn word_motions() {
+        let (mut vi, mut ed) = normal("foo.bar  baz", 0);
+        keys(&mut vi, &mut ed, "w");
+        assert_eq!(ed.idx, 3);
+        keys(&mut vi, &mut ed, "w");
+        assert_eq!(ed.idx, 4);
+        keys(&mut vi, &mut ed, "0W");
+        assert_eq!(ed.idx, 9);
+        keys(&mut vi, &mut ed, "b");
+        assert_eq!(ed.idx, 4);
+        keys(&mut vi, &mut ed, "0e");
+        assert_eq!(ed.idx, 2);
+        keys(&mut vi, &mut ed, "E");
+        assert_eq!(ed.idx, 6);
+    }
+
+    #[test]
+    fn finds() {
+        let (mut vi, mut ed) = normal("a,b,c,d", 0);
+        keys(&mut vi, &mut ed, "2f,");
+        assert_eq!(ed.idx, 3);
+        keys(&mut vi, &mut ed, "F,");
+        assert_eq!(ed.idx, 1);
+        keys(&mut vi, &mut ed, "dt,");
+        assert_eq!(ed.buf, "a,c,d");
+        keys(&mut vi, &mut ed, "0d2t,");
+        assert_eq!(ed.buf, ",d");
+    }
+
+    #[test]
+    fn deletes_and_puts() {
+        let (mut vi, mut ed) = normal("one two three", 0);
+        keys(&mut vi, &mut ed, "dw");
+        assert_eq!(ed.buf, "two three");
+        keys(&mut vi, &mut ed, "$p");
+        assert_eq!(ed.buf, "two threeone ");
+        keys(&mut vi, &mut ed, "u");
+        assert_eq!(ed.buf, "two three");
+        keys(&mut vi, &mut ed, "0x.");
+        assert_eq!(ed.buf, "o three");
+        keys(&mut vi, &mut ed, "D");
+        assert_eq!(ed.buf, "");
+    }
+
+    #[test]
+    fn repeats_changes() {
+        let (mut vi, mut ed) = normal("aa bb cc", 0);
+        match keys(&mut vi, &mut ed, "cw") {
+            ViAction::Mode => {}
+            _ => panic!("cw should go into insert mode"),
+        }
+        ed.insert("X");
+        vi.note_insert("X");
+        vi.enter_normal(&mut ed);
+        assert_eq!(ed.buf, "X bb cc");
+        keys(&mut vi, &mut ed, "w.");
+        assert_eq!(ed.buf, "X X cc");
+        assert_eq!(ed.idx, 2);
+    }
+
+    #[test]
+    fn replaces() {
+        let (mut vi, mut ed) = normal("abcd", 1);
+        keys(&mut vi, &mut ed, "2rx");
+        assert_eq!((ed.buf.as_str(), ed.idx), ("axxd", 2));
+        // not enough chars to replace
+        match keys(&mut vi, &mut ed, "5ry") {
+            ViAction::Bad => {}
+            _ => panic!("5ry should fail"),
+        }
+        assert_eq!(ed.buf, "axxd");
+    }
+}